@@ -1,28 +1,193 @@
+use crate::bands::BandAnalysis;
+pub use crate::bands::BandEnergy;
 use crate::cache::*;
+pub use crate::channels::ChannelLayout;
+pub use crate::command::CommandDecoder;
+pub use crate::decoder::{Decoder, DecoderRegistry};
+pub use crate::error::AnalysisError;
+use crate::format::AudioFormat;
+pub use crate::gain_policy::{Clamp, GainPolicy, GainPriority};
 use crate::models;
-use crate::models::AnalysisDifference;
-use crate::models::Entry;
+use crate::models::{AnalysisDifference, AnalysisFailure};
+use crate::models::{CueType, CueV2, Entry};
+use crate::mp3;
+use crate::mp4;
+use crate::mp4::{Mp4Info, Mp4Track};
+use crate::overs::PeakCounter;
+pub use crate::overs::{Overs, PeakHistogram};
+use crate::replaygain::ReplayGain;
+pub use crate::stereo::Stereo;
+use crate::stereo::StereoAnalysis;
 use crate::utils::*;
+use crate::weighting::GatedLoudness;
+pub use crate::weighting::Weighting;
 use audrey;
 use cfg_if::cfg_if;
 use claxon;
+use claxon::frame::FrameReader;
 use ebur128::{EbuR128, Mode};
-use hound;
 use log::{error, trace, warn};
+use ogg::{Packet as OggPacket, PacketReader};
+use opus_decoder::OpusMultistreamDecoder;
 use parking_lot::Mutex;
 use rayon::prelude::*;
-use rmp3::{Decoder, Frame::Audio};
-use std::convert::TryInto;
-use std::ffi::OsStr;
+use rmp3::{Frame::Audio, RawDecoder, MAX_SAMPLES_PER_FRAME};
+use serde::Serialize;
+use std::any::Any;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::io::{BufReader, Cursor, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder as CodecDecoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, Packet};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::default::formats::IsoMp4Reader;
+use symphonia::default::get_codecs;
 
-pub struct DecodedFile {
-    channels: u32,
-    rate: u32,
-    data: Vec<f32>, // interleaved
+/// Maximum number of frames handed to a `SampleSink` at once. Decoders never
+/// hold more than this in memory, so memory use per track is bounded
+/// regardless of the track length.
+pub const CHUNK_FRAMES: usize = 4096;
+
+/// Size of the read buffer used to stream MP3 files: a lot larger than the
+/// largest possible MPEG audio frame, so minimp3 can always sync.
+const MP3_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Most frames of the silence following the audio held as they are, to be
+/// measured should more audio come. Only the length of the rest is, measured
+/// as digital silence, for memory use to stay bounded over long silences.
+const HELD_SILENCE_FRAMES: usize = 16 * CHUNK_FRAMES;
+
+/// Interval between the sections measured to find the loudest one, and at
+/// which the short-term loudness is looked at for the highest.
+const SECTION_HOP: Duration = Duration::from_millis(100);
+
+/// Receives the audio of a file while it is being decoded.
+pub trait SampleSink {
+    /// Called before `format` when the file tells which speaker each channel
+    /// feeds, or its format has a convention for that.
+    fn channel_layout(&mut self, _layout: &ChannelLayout) -> Result<(), AnalysisError> {
+        Ok(())
+    }
+    /// Called once, before any sample is written.
+    fn format(&mut self, channels: u32, rate: u32) -> Result<(), AnalysisError>;
+    /// Called with interleaved samples, at most `CHUNK_FRAMES` frames at a time.
+    fn write(&mut self, samples: &[f32]) -> Result<(), AnalysisError>;
+}
+
+/// Buffers samples produced one at a time by a decoder, and hands them to a
+/// `SampleSink` in chunks of `CHUNK_FRAMES` frames.
+struct ChunkWriter<'a> {
+    sink: &'a mut dyn SampleSink,
+    buffer: Vec<f32>,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(sink: &'a mut dyn SampleSink, channels: u32, rate: u32) -> Result<Self, AnalysisError> {
+        if channels == 0 {
+            return Err(AnalysisError::Decode("no channels".to_string()));
+        }
+        sink.format(channels, rate)?;
+        Ok(ChunkWriter {
+            sink,
+            buffer: Vec::with_capacity(CHUNK_FRAMES * channels as usize),
+        })
+    }
+
+    /// Like `new`, first telling `sink` which speaker each channel feeds when
+    /// that is known.
+    fn with_layout(
+        sink: &'a mut dyn SampleSink,
+        channels: u32,
+        rate: u32,
+        layout: Option<ChannelLayout>,
+    ) -> Result<Self, AnalysisError> {
+        if let Some(layout) = layout {
+            sink.channel_layout(&layout)?;
+        }
+        ChunkWriter::new(sink, channels, rate)
+    }
+
+    fn push(&mut self, sample: f32) -> Result<(), AnalysisError> {
+        self.buffer.push(sample);
+        if self.buffer.len() == self.buffer.capacity() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), AnalysisError> {
+        if !self.buffer.is_empty() {
+            self.sink.write(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Hands the audio of a region of a file to a `SampleSink`, leaving out the
+/// rest.
+struct RegionSink<'a> {
+    sink: &'a mut dyn SampleSink,
+    region: Region,
+    channels: usize,
+    /// First frame of the region and the one past it, once the rate is known.
+    start: u64,
+    end: Option<u64>,
+    /// Frames received so far.
+    position: u64,
+}
+
+impl<'a> RegionSink<'a> {
+    fn new(sink: &'a mut dyn SampleSink, region: Region) -> Self {
+        RegionSink {
+            sink,
+            region,
+            channels: 0,
+            start: 0,
+            end: None,
+            position: 0,
+        }
+    }
+}
+
+impl SampleSink for RegionSink<'_> {
+    fn channel_layout(&mut self, layout: &ChannelLayout) -> Result<(), AnalysisError> {
+        self.sink.channel_layout(layout)
+    }
+
+    fn format(&mut self, channels: u32, rate: u32) -> Result<(), AnalysisError> {
+        let frames = |time: Duration| (time.as_secs_f64() * rate as f64).round() as u64;
+        self.channels = channels as usize;
+        self.start = frames(self.region.start);
+        self.end = self.region.end.map(frames);
+        self.sink.format(channels, rate)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), AnalysisError> {
+        if self.channels == 0 {
+            return self.sink.write(samples);
+        }
+        let frames = (samples.len() / self.channels) as u64;
+        let from = self.start.clamp(self.position, self.position + frames);
+        let to = self
+            .end
+            .unwrap_or(u64::MAX)
+            .clamp(from, self.position + frames);
+        let slice = |frame: u64| (frame - self.position) as usize * self.channels;
+        let result = if from < to {
+            self.sink.write(&samples[slice(from)..slice(to)])
+        } else {
+            Ok(())
+        };
+        self.position += frames;
+        result
+    }
 }
 
 fn i16_in_i32_to_float(integer: i32) -> f32 {
@@ -38,183 +203,2044 @@ fn i32_to_float(integer: i32) -> f32 {
     (integer as f32) / (2_u32.pow(31) as f32)
 }
 
-fn handle_audrey(path: &str) -> Result<DecodedFile, String> {
+fn handle_audrey(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
     let maybe_file = audrey::read::open(path);
     if let Ok(mut file) = maybe_file {
         let desc = file.description();
+        let mut writer = ChunkWriter::with_layout(
+            sink,
+            desc.channel_count(),
+            desc.sample_rate(),
+            ChannelLayout::vorbis(desc.channel_count()),
+        )?;
 
-        let data: Result<Vec<f32>, _> = file.samples().collect::<Result<Vec<f32>, _>>();
-
-        match data {
-            Ok(d) => Ok(DecodedFile {
-                channels: desc.channel_count(),
-                rate: desc.sample_rate(),
-                data: d,
-            }),
-            Err(_) => Err(format!("vorbis decoding error: {}", &path)),
+        for s in file.samples::<f32>() {
+            match s {
+                Ok(f) => writer.push(f)?,
+                Err(_) => {
+                    return Err(AnalysisError::Decode(format!(
+                        "vorbis decoding error: {}",
+                        &path
+                    )))
+                }
+            }
         }
+        writer.flush()
     } else {
-        Err(format!("file not found: {}", &path))
+        Err(AnalysisError::Io(format!("file not found: {}", &path)))
     }
 }
 
-fn handle_hound(path: &str) -> Result<DecodedFile, String> {
-    match hound::WavReader::open(path) {
-        Ok(mut reader) => {
-            let spec = reader.spec();
-            let data: Vec<f32> = match reader.spec().sample_format {
-                hound::SampleFormat::Int => {
-                    let conversion_function = match reader.spec().bits_per_sample {
-                        16 => i16_in_i32_to_float,
-                        24 => i24_to_float,
-                        32 => i32_to_float,
-                        _ => {
-                            return Err(format!(
-                                "Integer {} bits not supported",
-                                reader.spec().bits_per_sample
-                            ));
-                        }
-                    };
-                    reader
-                        .samples::<i32>()
-                        .map(Result::unwrap)
-                        .map(conversion_function)
-                        .collect()
+/// How samples are laid out in the `data` chunk of a WAV file.
+#[derive(Clone, Copy)]
+enum WavEncoding {
+    /// 8 bits samples, which are unsigned.
+    Unsigned8,
+    /// Little-endian signed integers, left-justified in their container when
+    /// they have fewer valid bits, e.g. 20 bits in 24.
+    Integer,
+    Float32,
+    Float64,
+}
+
+/// The `fmt ` chunk of a WAV file.
+struct WavFormat {
+    channels: u32,
+    rate: u32,
+    /// Bytes of each sample, the container size for integers.
+    sample_size: usize,
+    encoding: WavEncoding,
+    /// Speakers the channels feed, when the file uses WAVE_FORMAT_EXTENSIBLE.
+    channel_mask: Option<u32>,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// Size of the fmt chunk of `WAVE_FORMAT_EXTENSIBLE`, the largest one read.
+const WAV_FORMAT_SIZE: u64 = 40;
+
+fn parse_wav_format(fmt: &[u8]) -> Result<WavFormat, String> {
+    if fmt.len() < 16 {
+        return Err("fmt chunk too short".to_string());
+    }
+    let mut format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+    let channels = u16::from_le_bytes([fmt[2], fmt[3]]) as u32;
+    let rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]) as usize;
+    let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+    let mut channel_mask = None;
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        // cbSize, valid bits per sample, channel mask, then the GUID of the
+        // sub-format, which starts with the format tag it stands for
+        if fmt.len() < 40 {
+            return Err("fmt chunk too short".to_string());
+        }
+        channel_mask =
+            Some(u32::from_le_bytes(fmt[20..24].try_into().unwrap())).filter(|m| *m != 0);
+        format_tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+    }
+    if channels == 0 || block_align == 0 || !block_align.is_multiple_of(channels as usize) {
+        return Err(format!("invalid block size {}", block_align));
+    }
+    let sample_size = block_align / channels as usize;
+    let encoding = match (format_tag, sample_size) {
+        (WAVE_FORMAT_PCM, 1) => WavEncoding::Unsigned8,
+        (WAVE_FORMAT_PCM, 2..=4) => WavEncoding::Integer,
+        (WAVE_FORMAT_IEEE_FLOAT, 4) => WavEncoding::Float32,
+        (WAVE_FORMAT_IEEE_FLOAT, 8) => WavEncoding::Float64,
+        (WAVE_FORMAT_PCM, _) => return Err(format!("Integer {} bits not supported", bits)),
+        (WAVE_FORMAT_IEEE_FLOAT, _) => return Err(format!("Float {} bits not supported", bits)),
+        (tag, _) => return Err(format!("wav format {:#06x} not supported", tag)),
+    };
+    Ok(WavFormat {
+        channels,
+        rate,
+        sample_size,
+        encoding,
+        channel_mask,
+    })
+}
+
+fn wav_sample_to_float(bytes: &[u8], encoding: WavEncoding) -> f32 {
+    match encoding {
+        WavEncoding::Unsigned8 => (bytes[0] as f32 - 128.0) / 128.0,
+        WavEncoding::Float32 => f32::from_le_bytes(bytes.try_into().unwrap()),
+        WavEncoding::Float64 => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+        WavEncoding::Integer => {
+            // put them in the most significant bytes of an i32 so that any
+            // container size has the same scale
+            let mut integer = [0; 4];
+            integer[4 - bytes.len()..].copy_from_slice(bytes);
+            i32_to_float(i32::from_le_bytes(integer))
+        }
+    }
+}
+
+fn handle_wav(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+    let mut reader = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let invalid = || AnalysisError::Decode(format!("invalid wav: {}", &path));
+
+    let mut header = [0; 12];
+    reader.read_exact(&mut header).map_err(|_| invalid())?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid());
+    }
+
+    let mut format: Option<WavFormat> = None;
+    loop {
+        let mut chunk_header = [0; 8];
+        reader
+            .read_exact(&mut chunk_header)
+            .map_err(|_| invalid())?;
+        let size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+        match &chunk_header[0..4] {
+            b"fmt " => {
+                // whatever size the header claims, only what is parsed is
+                // read, the rest being skipped
+                let read = size.min(WAV_FORMAT_SIZE);
+                let mut fmt = [0; WAV_FORMAT_SIZE as usize];
+                let fmt = &mut fmt[..read as usize];
+                reader.read_exact(fmt).map_err(|_| invalid())?;
+                format = Some(
+                    parse_wav_format(fmt)
+                        .map_err(|e| AnalysisError::Decode(format!("{}: {}", e, &path)))?,
+                );
+                reader
+                    .seek_relative((size - read + size % 2) as i64)
+                    .map_err(|_| invalid())?;
+            }
+            b"data" => {
+                let format = match format {
+                    Some(f) => f,
+                    // the fmt chunk must come before the data
+                    None => return Err(invalid()),
+                };
+                let frame_size = format.sample_size * format.channels as usize;
+                let mut remaining = size / frame_size as u64;
+
+                let mut writer = ChunkWriter::with_layout(
+                    sink,
+                    format.channels,
+                    format.rate,
+                    format.channel_mask.map(ChannelLayout::from_mask),
+                )?;
+                let mut bytes = vec![0; CHUNK_FRAMES * frame_size];
+                while remaining > 0 {
+                    let count = remaining.min(CHUNK_FRAMES as u64) as usize;
+                    let chunk = &mut bytes[..count * frame_size];
+                    reader.read_exact(chunk).map_err(|_| invalid())?;
+                    for sample in chunk.chunks_exact(format.sample_size) {
+                        writer.push(wav_sample_to_float(sample, format.encoding))?;
+                    }
+                    remaining -= count as u64;
                 }
-                hound::SampleFormat::Float => reader.samples::<f32>().map(Result::unwrap).collect(),
-            };
-            Ok(DecodedFile {
-                channels: spec.channels.into(),
-                rate: spec.sample_rate,
-                data,
-            })
+                return writer.flush();
+            }
+            _ => {
+                reader
+                    .seek_relative((size + size % 2) as i64)
+                    .map_err(|_| invalid())?;
+            }
         }
-        Err(_) => Err("invalid wav".to_string()),
     }
 }
 
-fn handle_claxon(path: &str) -> Result<DecodedFile, String> {
+fn handle_claxon(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
     match claxon::FlacReader::open(path) {
         Ok(mut reader) => {
-            let conversion_function = match reader.streaminfo().bits_per_sample {
+            let spec = reader.streaminfo();
+            let conversion_function = match spec.bits_per_sample {
                 16 => i16_in_i32_to_float,
                 24 => i24_to_float,
                 32 => i32_to_float,
                 _ => {
-                    return Err("flac sample type not supported".to_string());
+                    return Err(AnalysisError::Decode(
+                        "flac sample type not supported".to_string(),
+                    ));
                 }
             };
-            let mut data = Vec::<f32>::new();
+            let mut writer = ChunkWriter::with_layout(
+                sink,
+                spec.channels,
+                spec.sample_rate,
+                ChannelLayout::flac(spec.channels),
+            )?;
             for s in reader.samples() {
                 match s {
                     Ok(f) => {
-                        data.push(conversion_function(f));
+                        writer.push(conversion_function(f))?;
                     }
                     Err(_) => {
-                        return Err(format!("invalid flac: {}", path));
+                        return Err(AnalysisError::Decode(format!("invalid flac: {}", path)));
                     }
                 }
             }
-            let spec = reader.streaminfo();
-            Ok(DecodedFile {
-                channels: spec.channels,
-                rate: spec.sample_rate,
-                data,
-            })
+            writer.flush()
+        }
+        Err(_) => Err(AnalysisError::Decode(format!("invalid flac: {}", &path))),
+    }
+}
+
+/// Largest possible FLAC frame: 65535 samples of 8 channels of 32 bits, stored
+/// verbatim, with room for the headers.
+const FLAC_MAX_FRAME_SIZE: usize = 65535 * 8 * 4 + 1024;
+
+/// Part of a FLAC file held in memory, from which frames are decoded and
+/// where the next one is looked for when they are damaged, the file never
+/// being read whole.
+struct FlacWindow {
+    file: File,
+    data: Vec<u8>,
+    /// Offset in the file of the start of `data`.
+    start: u64,
+    /// Offset in `data` of what's being decoded.
+    position: usize,
+    /// Whether the file was read to the end.
+    eof: bool,
+}
+
+impl FlacWindow {
+    fn new(file: File) -> Self {
+        FlacWindow {
+            file,
+            data: Vec::new(),
+            start: 0,
+            position: 0,
+            eof: false,
+        }
+    }
+
+    /// What's left from the position, at least `length` bytes unless the file
+    /// ends before. Twice as much is read at once, for what's left to be moved
+    /// to the start of the window once in a while.
+    fn fill(&mut self, length: usize) -> std::io::Result<&[u8]> {
+        if self.data.len() - self.position < length && !self.eof {
+            self.data.drain(..self.position);
+            self.start += self.position as u64;
+            self.position = 0;
+            let wanted = 2 * length - self.data.len();
+            let read = (&mut self.file)
+                .take(wanted as u64)
+                .read_to_end(&mut self.data)?;
+            self.eof = read < wanted;
+        }
+        Ok(&self.data[self.position..])
+    }
+
+    /// Goes `length` bytes further, without reading them.
+    fn skip(&mut self, length: usize) -> std::io::Result<()> {
+        let left = self.data.len() - self.position;
+        if length <= left {
+            self.position += length;
+        } else {
+            self.file
+                .seek(std::io::SeekFrom::Current((length - left) as i64))?;
+            self.start += (self.data.len() + length - left) as u64;
+            self.data.clear();
+            self.position = 0;
+        }
+        Ok(())
+    }
+
+    /// Offset in the file of what's being decoded.
+    fn offset(&self) -> u64 {
+        self.start + self.position as u64
+    }
+
+    /// Goes to the next frame header after the position, found by its sync
+    /// code, returning whether there is one.
+    fn next_frame(&mut self) -> std::io::Result<bool> {
+        self.position += 1;
+        loop {
+            let sync = self.data[self.position.min(self.data.len())..]
+                .windows(2)
+                .position(|w| w[0] == 0xff && (w[1] == 0xf8 || w[1] == 0xf9));
+            if let Some(offset) = sync {
+                self.position += offset;
+                return Ok(true);
+            }
+            if self.eof {
+                return Ok(false);
+            }
+            // the last byte can start a sync code
+            self.position = self.data.len().saturating_sub(1).max(self.position);
+            self.fill(FLAC_MAX_FRAME_SIZE)?;
+        }
+    }
+}
+
+/// Decodes a FLAC file frame by frame, looking for the next frame header when
+/// one of them is damaged. A window of the file larger than any frame is held
+/// in memory to be able to go back to the start of the damaged frame.
+fn handle_claxon_tolerant(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+    let invalid = || AnalysisError::Decode(format!("invalid flac: {}", &path));
+    let read_error = |_| AnalysisError::Io(format!("read error: {}", &path));
+    let mut window = match File::open(path) {
+        Ok(file) => FlacWindow::new(file),
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    if !window.fill(4).map_err(read_error)?.starts_with(b"fLaC") {
+        return Err(invalid());
+    }
+    window.skip(4).map_err(read_error)?;
+
+    // metadata blocks: STREAMINFO, then the others, the last one flagged and
+    // pictures and such skipped
+    let mut spec = None;
+    loop {
+        let header: [u8; 4] = match window.fill(4).map_err(read_error)?.get(..4) {
+            Some(header) => header.try_into().unwrap(),
+            None => return Err(invalid()),
+        };
+        window.skip(4).map_err(read_error)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        if header[0] & 0x7f == 0 {
+            let mut input = Cursor::new(window.fill(length as usize).map_err(read_error)?);
+            match claxon::metadata::read_metadata_block(&mut input, 0, length) {
+                Ok(claxon::metadata::MetadataBlock::StreamInfo(info)) => spec = Some(info),
+                _ => return Err(invalid()),
+            }
+        }
+        window.skip(length as usize).map_err(read_error)?;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    let spec = spec.ok_or_else(invalid)?;
+    let conversion_function = match spec.bits_per_sample {
+        16 => i16_in_i32_to_float,
+        24 => i24_to_float,
+        32 => i32_to_float,
+        _ => {
+            return Err(AnalysisError::Decode(
+                "flac sample type not supported".to_string(),
+            ));
+        }
+    };
+
+    let mut writer = ChunkWriter::with_layout(
+        sink,
+        spec.channels,
+        spec.sample_rate,
+        ChannelLayout::flac(spec.channels),
+    )?;
+    let mut buffer = Vec::new();
+    loop {
+        let mut input = Cursor::new(window.fill(FLAC_MAX_FRAME_SIZE).map_err(read_error)?);
+        let frame = FrameReader::new(&mut input).read_next_or_eof(std::mem::take(&mut buffer));
+        let length = input.position() as usize;
+        match frame {
+            Ok(Some(block)) => {
+                window.skip(length).map_err(read_error)?;
+                // a damaged header can pass its checksum
+                if block.channels() == spec.channels {
+                    for i in 0..block.duration() {
+                        for ch in 0..block.channels() {
+                            writer.push(conversion_function(block.sample(ch, i)))?;
+                        }
+                    }
+                }
+                buffer = block.into_buffer();
+            }
+            Ok(None) => break,
+            Err(_) => {
+                trace!(
+                    "skipping damaged flac frame at {}: {}",
+                    window.offset(),
+                    &path
+                );
+                if !window.next_frame().map_err(read_error)? {
+                    break;
+                }
+            }
         }
-        Err(_) => Err(format!("invalid flac: {}", &path)),
-    }
-}
-
-fn handle_minimp3(path: &str) -> Result<DecodedFile, String> {
-    match File::open(path) {
-        Ok(mut f) => {
-            let mut buffer = Vec::new();
-            // read the whole file
-            f.read_to_end(&mut buffer).unwrap();
-
-            let mut decoder = Decoder::new(&buffer);
-            let mut rate: u32 = 0;
-            let mut ch: u32 = 0;
-            let mut pcm_data = Vec::<f32>::new();
-            while let Some(frame) = decoder.next() {
-                if let Audio(audio) = frame {
-                    let sample_rate = audio.sample_rate();
-                    let channels = audio.channels() as u32;
-                    let samples = audio.samples();
-                    if rate != sample_rate && rate != 0 {
-                        return Err("inconsistent sample-rate".to_string());
-                    } else {
-                        rate = sample_rate;
+    }
+    writer.flush()
+}
+
+/// How samples are laid out in the `SSND` chunk of an AIFF or AIFF-C file.
+#[derive(Clone, Copy)]
+enum AiffEncoding {
+    BigEndian,
+    LittleEndian,
+    Float32,
+    Float64,
+}
+
+/// Size of the COMM chunk of AIFF-C up to its compression type, the largest
+/// part of it read.
+const AIFF_COMM_SIZE: u64 = 22;
+
+/// Converts an 80-bit IEEE 754 extended precision number, used by AIFF for the
+/// sample-rate, to a `f64`.
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let exponent = i32::from(u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7fff);
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+    let value = mantissa as f64 * 2_f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+fn aiff_sample_to_float(bytes: &[u8], encoding: AiffEncoding) -> f32 {
+    match encoding {
+        AiffEncoding::Float32 => f32::from_be_bytes(bytes.try_into().unwrap()),
+        AiffEncoding::Float64 => f64::from_be_bytes(bytes.try_into().unwrap()) as f32,
+        AiffEncoding::BigEndian | AiffEncoding::LittleEndian => {
+            // samples are left-justified: put them in the most significant
+            // bytes of an i32 so that any bit depth has the same scale
+            let mut integer = [0; 4];
+            integer[..bytes.len()].copy_from_slice(bytes);
+            if let AiffEncoding::LittleEndian = encoding {
+                integer[..bytes.len()].reverse();
+            }
+            i32_to_float(i32::from_be_bytes(integer))
+        }
+    }
+}
+
+fn handle_aiff(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+    let mut reader = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let invalid = || AnalysisError::Decode(format!("invalid aiff: {}", &path));
+
+    let mut header = [0; 12];
+    reader.read_exact(&mut header).map_err(|_| invalid())?;
+    if &header[0..4] != b"FORM" {
+        return Err(invalid());
+    }
+    let is_aifc = match &header[8..12] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(invalid()),
+    };
+
+    // channels, bits per sample, sample-rate, frame count and encoding, from
+    // the COMM chunk
+    let mut format: Option<(u32, usize, u32, u64, AiffEncoding)> = None;
+    loop {
+        let mut chunk_header = [0; 8];
+        reader
+            .read_exact(&mut chunk_header)
+            .map_err(|_| invalid())?;
+        let size = u32::from_be_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+        match &chunk_header[0..4] {
+            b"COMM" => {
+                if size < 18 {
+                    return Err(invalid());
+                }
+                // whatever size the header claims, only what is parsed is
+                // read, the rest being skipped
+                let read = size.min(AIFF_COMM_SIZE);
+                let mut comm = [0; AIFF_COMM_SIZE as usize];
+                let comm = &mut comm[..read as usize];
+                reader.read_exact(comm).map_err(|_| invalid())?;
+                let channels = u16::from_be_bytes([comm[0], comm[1]]) as u32;
+                let frames = u32::from_be_bytes(comm[2..6].try_into().unwrap()) as u64;
+                let bits = u16::from_be_bytes([comm[6], comm[7]]) as usize;
+                let rate = extended_to_f64(&comm[8..18]) as u32;
+                let encoding = if is_aifc {
+                    match comm.get(18..22) {
+                        Some(b"NONE") | Some(b"twos") => AiffEncoding::BigEndian,
+                        Some(b"sowt") => AiffEncoding::LittleEndian,
+                        Some(b"fl32") | Some(b"FL32") => AiffEncoding::Float32,
+                        Some(b"fl64") | Some(b"FL64") => AiffEncoding::Float64,
+                        Some(compression) => {
+                            return Err(AnalysisError::Decode(format!(
+                                "aifc compression {} not supported",
+                                String::from_utf8_lossy(compression)
+                            )));
+                        }
+                        None => return Err(invalid()),
+                    }
+                } else {
+                    AiffEncoding::BigEndian
+                };
+                let bits = match encoding {
+                    AiffEncoding::Float32 => 32,
+                    AiffEncoding::Float64 => 64,
+                    _ if (1..=32).contains(&bits) => bits,
+                    _ => {
+                        return Err(AnalysisError::Decode(format!(
+                            "Integer {} bits not supported",
+                            bits
+                        )));
                     }
-                    if ch != channels && ch != 0 {
-                        return Err("inconsistent channel count".to_string());
-                    } else {
-                        ch = channels.try_into().unwrap();
+                };
+                format = Some((channels, bits, rate, frames, encoding));
+                reader
+                    .seek_relative((size - read + size % 2) as i64)
+                    .map_err(|_| invalid())?;
+            }
+            b"SSND" => {
+                let (channels, bits, rate, frames, encoding) = match format {
+                    Some(f) => f,
+                    // the COMM chunk must come before the sound data
+                    None => return Err(invalid()),
+                };
+                let mut offset = [0; 8];
+                reader.read_exact(&mut offset).map_err(|_| invalid())?;
+                let offset = u32::from_be_bytes(offset[0..4].try_into().unwrap());
+                reader.seek_relative(offset.into()).map_err(|_| invalid())?;
+
+                let sample_size = bits.div_ceil(8);
+                let frame_size = sample_size * channels as usize;
+                if frame_size == 0 {
+                    return Err(invalid());
+                }
+                let available = size.saturating_sub(8 + offset as u64) / frame_size as u64;
+                let mut remaining = frames.min(available);
+
+                let mut writer = ChunkWriter::new(sink, channels, rate)?;
+                let mut bytes = vec![0; CHUNK_FRAMES * frame_size];
+                while remaining > 0 {
+                    let count = remaining.min(CHUNK_FRAMES as u64) as usize;
+                    let chunk = &mut bytes[..count * frame_size];
+                    reader.read_exact(chunk).map_err(|_| invalid())?;
+                    for sample in chunk.chunks_exact(sample_size) {
+                        writer.push(aiff_sample_to_float(sample, encoding))?;
                     }
-                    pcm_data.extend(samples);
+                    remaining -= count as u64;
                 }
+                return writer.flush();
+            }
+            _ => {
+                reader
+                    .seek_relative((size + size % 2) as i64)
+                    .map_err(|_| invalid())?;
             }
-            Ok(DecodedFile {
-                channels: ch,
-                rate,
-                data: pcm_data,
-            })
         }
-        _ => Err(format!("file not found: {}", &path)),
     }
 }
 
-#[derive(Debug)]
+/// Opus always decodes at 48kHz, whatever the sample-rate of the source was.
+const OPUS_RATE: u32 = 48000;
+
+/// Longest Opus packet: 120ms at 48kHz.
+const OPUS_MAX_FRAMES_PER_PACKET: usize = 5760;
+
+fn read_ogg_packet(
+    reader: &mut PacketReader<BufReader<File>>,
+    path: &str,
+) -> Result<Option<OggPacket>, AnalysisError> {
+    reader
+        .read_packet()
+        .map_err(|e| AnalysisError::Decode(format!("invalid ogg: {} ({})", &path, e)))
+}
+
+/// Decodes an Ogg Opus stream, as described in RFC 7845: the pre-skip and the
+/// end trimming of the last page are honoured, and the output gain of the
+/// `OpusHead` header is applied, like a player does.
+fn handle_opus(
+    path: &str,
+    mut reader: PacketReader<BufReader<File>>,
+    head: &[u8],
+    sink: &mut dyn SampleSink,
+) -> Result<(), AnalysisError> {
+    let invalid = || AnalysisError::Decode(format!("invalid opus: {}", &path));
+    if head.len() < 19 {
+        return Err(invalid());
+    }
+    let channels = head[9] as usize;
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+    // Q7.8 dB
+    let output_gain = i16::from_le_bytes([head[16], head[17]]) as f32 / 256.0;
+    // the Vorbis order, for the first two mapping families
+    let layout = match head[18] {
+        0 | 1 => ChannelLayout::vorbis(channels as u32),
+        _ => None,
+    };
+    let (streams, coupled, mapping) = match head[18] {
+        // mono or stereo, without a mapping table
+        0 if channels == 1 || channels == 2 => (1, channels - 1, vec![0, 1]),
+        0 => return Err(invalid()),
+        _ => match head.get(21..21 + channels) {
+            Some(mapping) => (head[19] as usize, head[20] as usize, mapping.to_vec()),
+            None => return Err(invalid()),
+        },
+    };
+    let mut decoder =
+        OpusMultistreamDecoder::new(OPUS_RATE, channels, streams, coupled, &mapping[..channels])
+            .map_err(|e| AnalysisError::Decode(format!("invalid opus: {} ({})", &path, e)))?;
+    let gain = 10_f32.powf(output_gain / 20.0);
+
+    // OpusTags
+    read_ogg_packet(&mut reader, path)?;
+
+    let mut writer = ChunkWriter::with_layout(sink, channels as u32, OPUS_RATE, layout)?;
+    let mut pcm = vec![0.0; OPUS_MAX_FRAMES_PER_PACKET * channels];
+    // The end trimming is only known from the granule position of the last
+    // page, which can have several packets: keep a page worth of audio around.
+    let mut page = Vec::new();
+    // frames decoded before the current page, including the pre-skip
+    let mut page_start: u64 = 0;
+    while let Some(packet) = read_ogg_packet(&mut reader, path)? {
+        let frames = decoder
+            .decode_float(&packet.data, &mut pcm, false)
+            .map_err(|e| AnalysisError::Decode(format!("invalid opus: {} ({})", &path, e)))?;
+        page.extend_from_slice(&pcm[..frames * channels]);
+        if !packet.last_in_page() {
+            continue;
+        }
+        let page_end = page_start + (page.len() / channels) as u64;
+        // granule positions include the pre-skip
+        let end = if packet.last_in_stream() {
+            packet.absgp_page().min(page_end)
+        } else {
+            page_end
+        };
+        let first = pre_skip.saturating_sub(page_start) as usize;
+        let last = end.saturating_sub(page_start) as usize;
+        if first < last {
+            for sample in &page[first * channels..last * channels] {
+                writer.push(sample * gain)?;
+            }
+        }
+        page.clear();
+        page_start = page_end;
+    }
+    writer.flush()
+}
+
+/// Decodes FLAC in an Ogg container: the first packet has the `STREAMINFO`
+/// block, then come the other metadata blocks, then one FLAC frame per packet.
+/// When `tolerant`, the packets that aren't a valid frame are skipped.
+fn handle_ogg_flac(
+    path: &str,
+    mut reader: PacketReader<BufReader<File>>,
+    head: &[u8],
+    sink: &mut dyn SampleSink,
+    tolerant: bool,
+) -> Result<(), AnalysisError> {
+    let invalid = || AnalysisError::Decode(format!("invalid flac: {}", &path));
+    // 0x7F "FLAC", version, number of header packets, "fLaC", then STREAMINFO
+    let header_packets = match head.get(7..9) {
+        Some(count) => u16::from_be_bytes([count[0], count[1]]),
+        None => return Err(invalid()),
+    };
+    let mut metadata = match head.get(13..) {
+        Some(metadata) => Cursor::new(metadata),
+        None => return Err(invalid()),
+    };
+    let spec = match claxon::metadata::read_metadata_block_with_header(&mut metadata) {
+        Ok(claxon::metadata::MetadataBlock::StreamInfo(spec)) => spec,
+        _ => return Err(invalid()),
+    };
+    let conversion_function = match spec.bits_per_sample {
+        16 => i16_in_i32_to_float,
+        24 => i24_to_float,
+        32 => i32_to_float,
+        _ => {
+            return Err(AnalysisError::Decode(
+                "flac sample type not supported".to_string(),
+            ));
+        }
+    };
+    for _ in 0..header_packets {
+        read_ogg_packet(&mut reader, path)?;
+    }
+
+    let mut writer = ChunkWriter::with_layout(
+        sink,
+        spec.channels,
+        spec.sample_rate,
+        ChannelLayout::flac(spec.channels),
+    )?;
+    let mut buffer = Vec::new();
+    while let Some(packet) = read_ogg_packet(&mut reader, path)? {
+        let mut frames = FrameReader::new(Cursor::new(&packet.data));
+        let block = match frames.read_next_or_eof(std::mem::take(&mut buffer)) {
+            Ok(Some(block)) if block.channels() == spec.channels || !tolerant => block,
+            Ok(_) => continue,
+            Err(_) if tolerant => continue,
+            Err(_) => return Err(invalid()),
+        };
+        for i in 0..block.duration() {
+            for ch in 0..block.channels() {
+                writer.push(conversion_function(block.sample(ch, i)))?;
+            }
+        }
+        buffer = block.into_buffer();
+    }
+    writer.flush()
+}
+
+/// Ogg files can have Vorbis, Opus or FLAC in them: the first packet tells.
+fn handle_ogg(path: &str, sink: &mut dyn SampleSink, tolerant: bool) -> Result<(), AnalysisError> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let mut reader = PacketReader::new(BufReader::new(file));
+    let head = match read_ogg_packet(&mut reader, path)? {
+        Some(packet) => packet.data,
+        None => return Err(AnalysisError::Decode(format!("invalid ogg: {}", &path))),
+    };
+    if head.starts_with(b"OpusHead") {
+        handle_opus(path, reader, &head, sink)
+    } else if head.starts_with(b"\x7fFLAC") {
+        handle_ogg_flac(path, reader, &head, sink, tolerant)
+    } else {
+        handle_audrey(path, sink)
+    }
+}
+
+/// Decodes an MP3 file, gaplessly when it has a LAME tag: the Xing frame
+/// isn't audio, and the encoder delay and padding are left out. minimp3 skips
+/// what it can't sync to; when `tolerant`, frames that don't have the format of
+/// the first one are taken for damaged ones, and skipped too.
+fn handle_minimp3(
+    path: &str,
+    sink: &mut dyn SampleSink,
+    tolerant: bool,
+) -> Result<(), AnalysisError> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let (audio_start, audio_end) = mp3::audio_range(&mut f)
+        .map_err(|_| AnalysisError::Io(format!("read error: {}", &path)))?;
+    let mut f = f.take(audio_end - audio_start);
+
+    let mut decoder = RawDecoder::new();
+    let mut pcm = [0.0; MAX_SAMPLES_PER_FRAME];
+    let mut buffer = vec![0; MP3_READ_BUFFER_SIZE];
+    // `start..end` is the part of `buffer` that hasn't been decoded yet
+    let mut start = 0;
+    let mut end = 0;
+    let mut eof = false;
+    let mut rate: u32 = 0;
+    let mut ch: u32 = 0;
+    let mut first_frame = true;
+    // frames to leave out at the start, and frames to keep after that, per channel
+    let mut skip: u64 = 0;
+    let mut remaining: Option<u64> = None;
+    loop {
+        // keep the buffer full so that a whole frame is always available
+        if !eof && end - start < MP3_READ_BUFFER_SIZE / 2 {
+            buffer.copy_within(start..end, 0);
+            end -= start;
+            start = 0;
+            while end < buffer.len() {
+                match f.read(&mut buffer[end..]) {
+                    Ok(0) => {
+                        eof = true;
+                        break;
+                    }
+                    Ok(read) => end += read,
+                    Err(_) => return Err(AnalysisError::Io(format!("read error: {}", &path))),
+                }
+            }
+        }
+        let (frame, consumed) = match decoder.next(&buffer[start..end], &mut pcm) {
+            Some(decoded) => decoded,
+            None => break,
+        };
+        if let Audio(audio) = frame {
+            let sample_rate = audio.sample_rate();
+            let channels = audio.channels() as u32;
+            if tolerant && rate != 0 && (rate != sample_rate || ch != channels) {
+                start += consumed;
+                continue;
+            }
+            if rate != sample_rate && rate != 0 {
+                return Err(AnalysisError::Decode(
+                    "inconsistent sample-rate".to_string(),
+                ));
+            }
+            if ch != channels && ch != 0 {
+                return Err(AnalysisError::Decode(
+                    "inconsistent channel count".to_string(),
+                ));
+            }
+            if rate == 0 {
+                rate = sample_rate;
+                ch = channels;
+                sink.format(ch, rate)?;
+            }
+            if first_frame {
+                first_frame = false;
+                if let Some(xing) = mp3::parse_xing(audio.source()) {
+                    if let Some((delay, padding)) = xing.gapless {
+                        skip = delay as u64 + mp3::DECODER_DELAY;
+                        remaining = xing.frames.map(|frames| {
+                            (frames as u64 * audio.sample_count() as u64)
+                                .saturating_sub(delay as u64 + padding as u64)
+                        });
+                    }
+                    // the Xing frame decodes to silence
+                    start += consumed;
+                    continue;
+                }
+            }
+            let frames = audio.sample_count() as u64;
+            let first = skip.min(frames);
+            skip -= first;
+            let last = match remaining {
+                Some(remaining) => frames.min(first + remaining),
+                None => frames,
+            };
+            if first < last {
+                // a frame is at most 1152 frames, well under `CHUNK_FRAMES`
+                sink.write(
+                    &audio.samples()[first as usize * ch as usize..last as usize * ch as usize],
+                )?;
+                if let Some(remaining) = remaining.as_mut() {
+                    *remaining -= last - first;
+                }
+            }
+        }
+        start += consumed;
+    }
+    Ok(())
+}
+
+/// Decoding state of one track of an MP4 file, that leaves out the encoder
+/// priming and padding described by the track's edit list or iTunes metadata.
+struct Mp4TrackDecoder<'a> {
+    index: usize,
+    decoder: Box<dyn CodecDecoder>,
+    buffer: Option<SampleBuffer<f32>>,
+    // frames decoded so far, including the priming
+    position: u64,
+    // part of the decoded audio that is played, in frames
+    start: u64,
+    end: Option<u64>,
+    sink: &'a mut dyn SampleSink,
+}
+
+impl Mp4TrackDecoder<'_> {
+    fn decode(&mut self, path: &str, packet: &Packet) -> Result<(), AnalysisError> {
+        let decoded = match self.decoder.decode(packet) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                return Err(AnalysisError::Decode(format!(
+                    "invalid mp4: {} ({})",
+                    &path, e
+                )))
+            }
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        if channels == 0 {
+            return Err(AnalysisError::Decode(format!("no channels: {}", &path)));
+        }
+        if self.buffer.is_none() {
+            // the channels come in the order of their bits, as in WAV files
+            let layout = ChannelLayout::from_mask(spec.channels.bits());
+            if layout.channels() == channels as u32 {
+                self.sink.channel_layout(&layout)?;
+            }
+            self.sink.format(channels as u32, spec.rate)?;
+        }
+        if self
+            .buffer
+            .as_ref()
+            .is_none_or(|b| b.capacity() < decoded.capacity() * channels)
+        {
+            self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        let buffer = self.buffer.as_mut().unwrap();
+        buffer.copy_interleaved_ref(decoded);
+
+        // only keep the part of this packet that is between `start` and `end`
+        let frames = (buffer.len() / channels) as u64;
+        let first = self.start.saturating_sub(self.position).min(frames) as usize;
+        let last = self
+            .end
+            .map_or(frames, |end| end.saturating_sub(self.position).min(frames))
+            as usize;
+        self.position += frames;
+        if first < last {
+            let samples = &buffer.samples()[first * channels..last * channels];
+            for chunk in samples.chunks(CHUNK_FRAMES * channels) {
+                self.sink.write(chunk)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes tracks of the MP4 file at `path` with Symphonia, in a single pass
+/// over the file. Each `(index, sink)` pair receives the audio of the track
+/// `index`.
+fn decode_mp4_tracks(
+    path: &str,
+    info: &Mp4Info,
+    sinks: Vec<(usize, &mut dyn SampleSink)>,
+) -> Result<(), AnalysisError> {
+    let invalid =
+        |e: SymphoniaError| AnalysisError::Decode(format!("invalid mp4: {} ({})", &path, e));
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut reader = IsoMp4Reader::try_new(stream, &FormatOptions::default()).map_err(invalid)?;
+
+    let mut decoders = Vec::new();
+    for (index, sink) in sinks {
+        let (params, track) = match (reader.tracks().get(index), info.tracks.get(index)) {
+            (Some(t), Some(track)) => (t.codec_params.clone(), track),
+            _ => return Err(AnalysisError::Decode(format!("invalid mp4: {}", &path))),
+        };
+        let decoder = get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .map_err(|e| {
+                AnalysisError::Decode(format!("mp4 codec not supported: {} ({})", &path, e))
+            })?;
+
+        // edits are in the media time scale, convert them to frames
+        let gapless = track.gapless.unwrap_or_default();
+        let to_frames = |time: u64| match params.sample_rate {
+            Some(rate) if track.timescale != 0 => time * rate as u64 / track.timescale as u64,
+            _ => time,
+        };
+        let start = to_frames(gapless.priming);
+        decoders.push(Mp4TrackDecoder {
+            index,
+            decoder,
+            buffer: None,
+            position: 0,
+            start,
+            end: gapless.duration.map(|d| start + to_frames(d)),
+            sink,
+        });
+    }
+
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(invalid(e)),
+        };
+        if let Some(decoder) = decoders
+            .iter_mut()
+            .find(|d| d.index as u32 == packet.track_id())
+        {
+            decoder.decode(path, &packet)?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_mp4(path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+    let info = mp4::read(path)?;
+    match info.tracks.iter().position(Mp4Track::is_audio) {
+        Some(index) => decode_mp4_tracks(path, &info, vec![(index, sink)]),
+        None => Err(AnalysisError::Decode(format!("no audio track: {}", &path))),
+    }
+}
+
+/// Traktor STEM files are MP4 files with the stereo master as the first audio
+/// track, followed by the four stems.
+pub fn is_stem_file(path: &str) -> bool {
+    path.to_lowercase().ends_with(".stem.mp4")
+}
+
+/// Feeds decoded audio to an EBU R128 meter.
+#[derive(Default)]
+struct LoudnessMeter {
+    ebu: Option<EbuR128>,
+    channels: u32,
+    rate: u32,
+    /// Speakers the channels feed, when the file tells.
+    layout: Option<ChannelLayout>,
+    /// Layout of the files that don't tell theirs, when they have as many
+    /// channels.
+    default_layout: Option<ChannelLayout>,
+    /// Samples per channel received so far.
+    sample_count: u64,
+    /// Samples per channel measured so far, which leaves out the silence at
+    /// the edges when it is excluded.
+    measured_count: u64,
+    /// Finds the silence at the edges, when asked to.
+    silence: Option<SilenceDetector>,
+    /// Algorithm measuring the loudness the gain is derived from.
+    algorithm: LoudnessAlgorithm,
+    /// ReplayGain 1 measurement, when that's the algorithm and it supports
+    /// the rate.
+    replaygain: Option<ReplayGain>,
+    /// Sum of the squares of the samples of each channel.
+    squares: Vec<f64>,
+    /// Filters weighting the audio when measured with BS.1770.
+    weighting: Weighting,
+    /// BS.1770 measurement through other filters than the K-weighting of
+    /// ebur128, when that's the weighting.
+    weighted: Option<GatedLoudness>,
+    /// Whether to analyse the stereo image of stereo audio.
+    analyse_stereo: bool,
+    stereo: Option<StereoAnalysis>,
+    /// Whether to measure the band energy.
+    analyse_bands: bool,
+    bands: Option<BandAnalysis>,
+    /// Whether to count the samples over each level, for the overs.
+    count_overs: bool,
+    peaks: Option<PeakCounter>,
+    /// Interval at which the momentary and short-term loudness are recorded,
+    /// when they are.
+    timeline_hop: Option<Duration>,
+    /// `timeline_hop` in samples per channel, once the rate is known.
+    hop_frames: Option<u64>,
+    timeline: Vec<LoudnessPoint>,
+    /// Sections to measure the loudest of, when the gain is based on it.
+    loudest_section: Option<LoudestSection>,
+    /// `SECTION_HOP` in samples per channel, once the rate is known.
+    section_hop_frames: Option<u64>,
+    /// Loudness of each section measured so far.
+    section_loudness: Vec<f64>,
+    /// Highest short-term loudness so far.
+    max_short_term: f64,
+}
+
+/// What a `LoudnessMeter` measured.
+struct Measurement {
+    integrated_loudness: f32,
+    true_peak: f32,
+    loudness_range: f32,
+    sample_count: u64,
+    sample_rate: u32,
+    channels: u32,
+    timeline: Option<Vec<LoudnessPoint>>,
+    section_loudness: Option<SectionLoudness>,
+    silence: Option<Silence>,
+    /// Loudness measured by the algorithm.
+    loudness: f32,
+    dynamics: Dynamics,
+    stereo: Option<Stereo>,
+    band_energy: Option<BandEnergy>,
+    peak_histogram: Option<PeakHistogram>,
+    /// Algorithm `loudness` was measured with.
+    algorithm: LoudnessAlgorithm,
+}
+
+/// Finds where the audio of a file starts and ends, the rest being silence.
+struct SilenceDetector {
+    /// Level of the samples above which the audio isn't silent, in linear.
+    threshold: f32,
+    /// Whether the silence is left out of the measurement.
+    exclude: bool,
+    /// First frame that isn't silent, once there is one.
+    start: Option<u64>,
+    /// Frame past the last one that isn't silent.
+    end: u64,
+    /// Silent samples following the audio so far, measured once more audio
+    /// comes and left out if none does.
+    pending: Vec<f32>,
+    /// Frames of that silence past the `HELD_SILENCE_FRAMES` first ones.
+    pending_frames: u64,
+}
+
+impl SilenceDetector {
+    /// Holds the silent `samples` following the audio, but for the frames
+    /// past `HELD_SILENCE_FRAMES`, which are only counted.
+    fn hold(&mut self, samples: &[f32], channels: usize) {
+        let room = (HELD_SILENCE_FRAMES * channels)
+            .saturating_sub(self.pending.len())
+            .min(samples.len());
+        self.pending.extend_from_slice(&samples[..room]);
+        self.pending_frames += ((samples.len() - room) / channels) as u64;
+    }
+}
+
+impl LoudnessMeter {
+    fn new(options: &AnalysisOptions) -> Self {
+        LoudnessMeter {
+            default_layout: options.channel_layout.clone(),
+            timeline_hop: options.timeline_hop,
+            loudest_section: match options.gain_basis {
+                GainBasis::Integrated => None,
+                GainBasis::LoudestSection(section) => Some(section),
+            },
+            silence: options.silence_threshold.map(|threshold| SilenceDetector {
+                threshold: db_to_linear(threshold as f32),
+                exclude: options.exclude_silence,
+                start: None,
+                end: 0,
+                pending: Vec::new(),
+                pending_frames: 0,
+            }),
+            algorithm: options.algorithm,
+            weighting: options.weighting.clone(),
+            max_short_term: f64::NEG_INFINITY,
+            analyse_stereo: options.stereo,
+            analyse_bands: options.band_energy,
+            count_overs: options.count_overs,
+            ..Default::default()
+        }
+    }
+
+    /// Finds the silence in `samples`, the frames following the
+    /// `sample_count` first ones, and measures them, but for the silence at
+    /// the edges when it is excluded.
+    fn add_samples(&mut self, samples: &[f32]) -> Result<(), AnalysisError> {
+        let channels = self.channels as usize;
+        let first = self.sample_count;
+        self.sample_count += (samples.len() / channels) as u64;
+        let detector = match self.silence.as_mut() {
+            Some(detector) => detector,
+            None => return self.add_frames(samples),
+        };
+        let loud = |frame: &[f32]| frame.iter().any(|s| s.abs() > detector.threshold);
+        let start = samples.chunks_exact(channels).position(loud);
+        let end = samples
+            .chunks_exact(channels)
+            .rposition(loud)
+            .map(|last| last + 1);
+        let starts_here = detector.start.is_none() && start.is_some();
+        if let (Some(start), Some(end)) = (start, end) {
+            detector.start.get_or_insert(first + start as u64);
+            detector.end = first + end as u64;
+        }
+        if !detector.exclude {
+            return self.add_frames(samples);
+        }
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                let from = if starts_here { start } else { 0 };
+                let (audio, silence) = samples.split_at(end * channels);
+                self.add_pending()?;
+                self.add_frames(&audio[from * channels..])?;
+                // the detector is still there
+                self.silence.as_mut().unwrap().hold(silence, channels);
+            }
+            // silence before the audio is dropped, after it kept for later
+            _ if detector.start.is_some() => detector.hold(samples, channels),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Measures the silence held since the audio last stopped, now that more
+    /// has come.
+    fn add_pending(&mut self) -> Result<(), AnalysisError> {
+        // there is a detector when silence is excluded
+        let detector = self.silence.as_mut().unwrap();
+        let mut pending = std::mem::take(&mut detector.pending);
+        let mut frames = std::mem::take(&mut detector.pending_frames);
+        self.add_frames(&pending)?;
+        // the detector keeps its buffer
+        pending.clear();
+        self.silence.as_mut().unwrap().pending = pending;
+        let channels = self.channels as usize;
+        let digital_silence = vec![0.0; frames.min(CHUNK_FRAMES as u64) as usize * channels];
+        while frames > 0 {
+            let chunk = frames.min(CHUNK_FRAMES as u64) as usize;
+            self.add_frames(&digital_silence[..chunk * channels])?;
+            frames -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Measures `samples`, recording the loudness each time a hop ends.
+    fn add_frames(&mut self, mut samples: &[f32]) -> Result<(), AnalysisError> {
+        // there is one once the format is known
+        let ebu = self.ebu.as_mut().unwrap();
+        let measure_error = |e: ebur128::Error| AnalysisError::Measure(e.to_string());
+        let channels = self.channels as usize;
+        if let Some(replaygain) = self.replaygain.as_mut() {
+            replaygain.add(samples);
+        }
+        if let Some(weighted) = self.weighted.as_mut() {
+            weighted.add(samples);
+        }
+        if let Some(stereo) = self.stereo.as_mut() {
+            stereo.add(samples);
+        }
+        if let Some(bands) = self.bands.as_mut() {
+            bands.add(samples);
+        }
+        if let Some(peaks) = self.peaks.as_mut() {
+            peaks.add(samples);
+        }
+        for frame in samples.chunks_exact(channels) {
+            for (square, sample) in self.squares.iter_mut().zip(frame) {
+                *square += (*sample as f64) * (*sample as f64);
+            }
+        }
+        let hops = [self.hop_frames, self.section_hop_frames];
+        // the silence left out before the audio, hops being in the time of
+        // the file
+        let skipped = match &self.silence {
+            Some(detector) if detector.exclude => detector.start.unwrap_or(0),
+            _ => 0,
+        };
+        while samples.len() >= channels {
+            // there is at least one hop
+            let until_hop = hops
+                .iter()
+                .flatten()
+                .map(|hop| hop - (skipped + self.measured_count) % hop)
+                .min()
+                .unwrap();
+            let frames = (samples.len() / channels).min(until_hop as usize);
+            let (now, rest) = samples.split_at(frames * channels);
+            ebu.add_frames_f32(now).map_err(measure_error)?;
+            self.measured_count += frames as u64;
+            let position = skipped + self.measured_count;
+            let at_hop = |hop: Option<u64>| hop.is_some_and(|hop| position.is_multiple_of(hop));
+            if at_hop(self.hop_frames) {
+                self.timeline.push(LoudnessPoint {
+                    time: position as f64 / self.rate as f64,
+                    momentary: ebu.loudness_momentary().map_err(measure_error)?,
+                    short_term: ebu.loudness_shortterm().map_err(measure_error)?,
+                });
+            }
+            if at_hop(self.section_hop_frames) {
+                let short_term = ebu.loudness_shortterm().map_err(measure_error)?;
+                self.max_short_term = self.max_short_term.max(short_term);
+            }
+            if let Some(section) = self
+                .loudest_section
+                .filter(|_| at_hop(self.section_hop_frames))
+            {
+                // only whole sections are measured
+                let length = section.length.as_secs_f64();
+                if self.measured_count as f64 >= length * self.rate as f64 {
+                    let loudness = ebu
+                        .loudness_window(section.length.as_millis() as u32)
+                        .map_err(measure_error)?;
+                    self.section_loudness.push(loudness);
+                }
+            }
+            samples = rest;
+        }
+        Ok(())
+    }
+
+    /// Whether there was audio, but all of it was silence left out of the
+    /// measurement.
+    fn only_silence(&self) -> bool {
+        self.sample_count > 0 && self.measured_count == 0
+    }
+
+    fn finish(self, path: &str) -> Result<Measurement, AnalysisError> {
+        let ebu = match self.ebu {
+            Some(ebu) if self.sample_count > 0 => ebu,
+            _ => return Err(AnalysisError::Measure(format!("no audio: {}", &path))),
+        };
+        if self.measured_count == 0 {
+            return Err(AnalysisError::Measure(format!("only silence: {}", &path)));
+        }
+        let seconds = |frames: u64| frames as f64 / self.rate as f64;
+        let silence = self.silence.as_ref().map(|detector| match detector.start {
+            Some(start) => Silence {
+                leading: seconds(start),
+                trailing: seconds(self.sample_count - detector.end),
+            },
+            None => Silence {
+                leading: seconds(self.sample_count),
+                trailing: 0.0,
+            },
+        });
+        let measure_error = |e: ebur128::Error| AnalysisError::Measure(format!("{}: {}", &path, e));
+
+        // find max peak of all channels: the model has a single value for the peak
+        let mut max_peak = 0.0;
+        for i in 0..ebu.channels() {
+            let peak = ebu.true_peak(i).map_err(measure_error)?;
+            if max_peak < peak {
+                max_peak = peak;
+            }
+        }
+        let integrated_loudness = ebu.loudness_global().map_err(measure_error)? as f32;
+        let section_loudness = self.loudest_section.map(|section| SectionLoudness {
+            section,
+            loudness: section
+                .loudness(&self.section_loudness)
+                // tracks shorter than a section are taken whole
+                .unwrap_or(integrated_loudness),
+        });
+        // of all channels together
+        let mean_squares: f64 = self
+            .squares
+            .iter()
+            .map(|square| square / self.measured_count as f64)
+            .sum();
+        let loudness = match self.algorithm {
+            LoudnessAlgorithm::Bs1770 => match &self.weighted {
+                Some(weighted) => weighted.loudness() as f32,
+                None => integrated_loudness,
+            },
+            LoudnessAlgorithm::ReplayGain1 => match &self.replaygain {
+                // ReplayGain's reference level is that of -18 LUFS
+                Some(replaygain) => match replaygain.gain() {
+                    Some(gain) => (-18.0 - gain) as f32,
+                    None => return Err(AnalysisError::Measure(format!("too short: {}", &path))),
+                },
+                None => {
+                    warn!(
+                        "ReplayGain 1 doesn't support {}Hz, measuring {} with BS.1770 instead",
+                        self.rate, path
+                    );
+                    integrated_loudness
+                }
+            },
+            // on the scale of BS.1770, without its weighting and gating
+            LoudnessAlgorithm::Rms => (-0.691 + 10.0 * mean_squares.log10()) as f32,
+            LoudnessAlgorithm::Peak => linear_to_db(max_peak as f32),
+        };
+        let mut sample_peak = 0f64;
+        for i in 0..ebu.channels() {
+            sample_peak = sample_peak.max(ebu.sample_peak(i).map_err(measure_error)?);
+        }
+        let true_peak_db = linear_to_db(max_peak as f32);
+        let dynamics = Dynamics {
+            plr: true_peak_db - integrated_loudness,
+            psr: true_peak_db - self.max_short_term as f32,
+            crest_factor: linear_to_db(sample_peak as f32)
+                - (10.0 * (mean_squares / ebu.channels() as f64).log10()) as f32,
+        };
+        Ok(Measurement {
+            integrated_loudness,
+            true_peak: max_peak as f32,
+            loudness_range: ebu.loudness_range().map_err(measure_error)? as f32,
+            sample_count: self.sample_count,
+            sample_rate: self.rate,
+            channels: self.channels,
+            timeline: self.timeline_hop.map(|_| self.timeline),
+            section_loudness,
+            silence,
+            loudness,
+            dynamics,
+            stereo: self.stereo.as_ref().map(StereoAnalysis::finish),
+            band_energy: self.bands.as_ref().and_then(BandAnalysis::finish),
+            peak_histogram: self.peaks.as_ref().map(PeakCounter::finish),
+            algorithm: match self.algorithm {
+                LoudnessAlgorithm::ReplayGain1 if self.replaygain.is_none() => {
+                    LoudnessAlgorithm::Bs1770
+                }
+                algorithm => algorithm,
+            },
+        })
+    }
+}
+
+impl SampleSink for LoudnessMeter {
+    fn channel_layout(&mut self, layout: &ChannelLayout) -> Result<(), AnalysisError> {
+        self.layout = Some(layout.clone());
+        Ok(())
+    }
+
+    fn format(&mut self, channels: u32, rate: u32) -> Result<(), AnalysisError> {
+        let unsupported = |reason: String| {
+            AnalysisError::Measure(format!(
+                "can't meter {} channels at {}Hz: {}",
+                channels, rate, reason
+            ))
+        };
+        if channels == 0 || rate == 0 {
+            return Err(unsupported("no audio".to_string()));
+        }
+        let mut mode = Mode::I | Mode::S | Mode::LRA | Mode::SAMPLE_PEAK | Mode::TRUE_PEAK;
+        if self.timeline_hop.is_some() {
+            mode |= Mode::M;
+        }
+        match EbuR128::new(channels, rate, mode) {
+            Ok(mut ebu) => {
+                if let Some(section) = self.loudest_section {
+                    ebu.set_max_window(section.length.as_millis() as u32)
+                        .map_err(|e| unsupported(e.to_string()))?;
+                }
+                let default_layout = self
+                    .default_layout
+                    .as_ref()
+                    .filter(|layout| layout.channels() == channels);
+                // otherwise, ebur128 takes the first channels for L R C - Ls Rs
+                if let Some(layout) = self.layout.as_ref().or(default_layout) {
+                    ebu.set_channel_map(&layout.channel_map(channels))
+                        .map_err(|e| unsupported(e.to_string()))?;
+                }
+                if self.algorithm == LoudnessAlgorithm::ReplayGain1 {
+                    // other rates are measured with BS.1770 instead
+                    self.replaygain = ReplayGain::new(channels, rate).ok();
+                }
+                if self.algorithm == LoudnessAlgorithm::Bs1770 && self.weighting != Weighting::K {
+                    self.weighted = Some(GatedLoudness::new(
+                        &self.weighting.filters(rate)?,
+                        ebu.channel_map(),
+                        rate,
+                    ));
+                }
+                if self.analyse_stereo && channels == 2 {
+                    self.stereo = Some(StereoAnalysis::new(rate)?);
+                }
+                if self.analyse_bands {
+                    self.bands = Some(BandAnalysis::new(channels, rate));
+                }
+                if self.count_overs {
+                    self.peaks = Some(PeakCounter::new(channels, rate));
+                }
+                self.squares = vec![0.0; channels as usize];
+                self.ebu = Some(ebu);
+                self.channels = channels;
+                self.rate = rate;
+                self.hop_frames = self
+                    .timeline_hop
+                    .map(|hop| ((hop.as_secs_f64() * rate as f64).round() as u64).max(1));
+                self.section_hop_frames =
+                    Some(((SECTION_HOP.as_secs_f64() * rate as f64).round() as u64).max(1));
+                Ok(())
+            }
+            Err(e) => Err(unsupported(e.to_string())),
+        }
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), AnalysisError> {
+        if self.ebu.is_none() {
+            return Err(AnalysisError::Measure(
+                "samples written before the format".to_string(),
+            ));
+        }
+        self.add_samples(samples)
+    }
+}
+
+/// What to measure, on top of the loudness and peak of each file.
+#[derive(Debug, Clone)]
+pub struct AnalysisOptions {
+    /// Measure each stem of Traktor STEM files.
+    pub stems: bool,
+    /// The decoders to decode files with.
+    pub decoders: Arc<DecoderRegistry>,
+    /// Skip the damaged parts of files, measuring the rest.
+    pub tolerant: bool,
+    /// When tolerant, percentage of the duration Traktor has for a track below
+    /// which what was measured isn't trusted, and the gain left untouched.
+    pub min_coverage: f64,
+    /// Layout of the files with that many channels whose format doesn't tell
+    /// theirs, e.g. AIFF files or WAV files without a channel mask.
+    pub channel_layout: Option<ChannelLayout>,
+    /// Record the momentary and short-term loudness at this interval, in
+    /// `ComputedLoudness::timeline`.
+    pub timeline_hop: Option<Duration>,
+    /// What the gain of tracks is derived from.
+    pub gain_basis: GainBasis,
+    /// Region of the tracks of a collection to measure, going by their cues.
+    pub cue_region: Option<CueRegion>,
+    /// Level in dBFS below which the audio at the edges of files is taken for
+    /// silence, finding it when there is one.
+    pub silence_threshold: Option<f64>,
+    /// Leave the silence found at the edges out of the measurement.
+    pub exclude_silence: bool,
+    /// Algorithm measuring the loudness the gain is derived from.
+    pub algorithm: LoudnessAlgorithm,
+    /// Filters weighting the frequencies of the audio before BS.1770 gates
+    /// it, for the loudness the gain is derived from. The other measurements
+    /// are K-weighted whatever it is.
+    pub weighting: Weighting,
+    /// Limits to the gain written to the tracks of a collection.
+    pub gain_policy: GainPolicy,
+    /// Count the samples of the tracks of a collection over 0dBTP, and over
+    /// the ceiling of `gain_policy`, once their gain is applied, from
+    /// `ComputedLoudness::peak_histogram`.
+    pub count_overs: bool,
+    /// Analyse how stereo files hold up in mono, in `ComputedLoudness::stereo`.
+    pub stereo: bool,
+    /// Loss of loudness in LU in mono beyond which tracks are flagged.
+    pub max_mono_loss: f32,
+    /// Measure how the energy of files is spread across the spectrum, in
+    /// `ComputedLoudness::band_energy`.
+    pub band_energy: bool,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        AnalysisOptions {
+            stems: false,
+            decoders: Default::default(),
+            tolerant: false,
+            min_coverage: 95.0,
+            channel_layout: None,
+            timeline_hop: None,
+            gain_basis: GainBasis::Integrated,
+            cue_region: None,
+            silence_threshold: None,
+            exclude_silence: false,
+            algorithm: LoudnessAlgorithm::Bs1770,
+            weighting: Weighting::K,
+            gain_policy: GainPolicy::default(),
+            count_overs: false,
+            stereo: false,
+            max_mono_loss: 3.0,
+            band_energy: false,
+        }
+    }
+}
+
+/// How the loudness the gain of tracks is derived from is measured. Whatever
+/// the algorithm, the other measurements are those of EBU R128.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoudnessAlgorithm {
+    /// The integrated loudness of ITU BS.1770, which EBU R128 uses.
+    #[default]
+    Bs1770,
+    /// ReplayGain 1.0, as most DJ software before BS.1770 did, on the LUFS
+    /// scale: its reference level is taken for -18 LUFS. Only for audio at
+    /// 44100 or 48000Hz.
+    ReplayGain1,
+    /// The level of all the audio, unweighted and ungated, on the LUFS scale.
+    Rms,
+    /// The true peak in dBTP, to normalize by peak: the target loudness is
+    /// then that of the peaks.
+    Peak,
+}
+
+impl LoudnessAlgorithm {
+    pub const ALL: [LoudnessAlgorithm; 4] = [
+        LoudnessAlgorithm::Bs1770,
+        LoudnessAlgorithm::ReplayGain1,
+        LoudnessAlgorithm::Rms,
+        LoudnessAlgorithm::Peak,
+    ];
+
+    /// Name of the algorithm, as given on the command line and cached.
+    pub fn name(self) -> &'static str {
+        match self {
+            LoudnessAlgorithm::Bs1770 => "bs1770",
+            LoudnessAlgorithm::ReplayGain1 => "replaygain1",
+            LoudnessAlgorithm::Rms => "rms",
+            LoudnessAlgorithm::Peak => "peak",
+        }
+    }
+}
+
+impl FromStr for LoudnessAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LoudnessAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown loudness algorithm: {}", s))
+    }
+}
+
+/// The sections of a track, all `length` long and starting 100 ms apart, its
+/// loudest section is picked from: the one at `percentile`, 100 being the
+/// loudest of all. Lower percentiles keep a short burst from being picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudestSection {
+    pub length: Duration,
+    pub percentile: f64,
+}
+
+impl LoudestSection {
+    /// The loudness at `percentile` of the sections of `loudness`, leaving out
+    /// the silent ones, if any isn't.
+    fn loudness(&self, loudness: &[f64]) -> Option<f32> {
+        let mut loudness: Vec<f64> = loudness.iter().copied().filter(|l| l.is_finite()).collect();
+        if loudness.is_empty() {
+            return None;
+        }
+        loudness.sort_by(f64::total_cmp);
+        // nearest rank
+        let rank = (self.percentile / 100.0 * loudness.len() as f64).ceil() as usize;
+        Some(loudness[rank.clamp(1, loudness.len()) - 1] as f32)
+    }
+}
+
+/// What the gain of tracks is derived from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainBasis {
+    /// The integrated loudness of the whole track.
+    Integrated,
+    /// The loudness of its loudest section, e.g. the main drop of club tracks,
+    /// so that long quiet intros don't make it louder than the others.
+    LoudestSection(LoudestSection),
+}
+
+/// Part of a track, from `start` to `end`, or to the end of the track without
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+/// The region of tracks to measure, going by their Traktor cues, so that the
+/// intros and outros they aren't played with don't count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CueRegion {
+    /// From the first cue of a type to the first cue of another type after
+    /// it. A missing cue, or no type, leaves that end of the track as is.
+    Between(Option<CueType>, Option<CueType>),
+    /// Within the first saved loop.
+    Loop,
+}
+
+impl CueRegion {
+    /// The region of a track with `cues` to measure, if it isn't the whole
+    /// track.
+    pub fn region(&self, cues: &[CueV2]) -> Option<Region> {
+        let ms = |ms: f64| Duration::from_secs_f64(ms.max(0.0) / 1000.0);
+        // the cues of a type, the earliest first
+        let of_type = |cue_type: CueType| {
+            let mut starts: Vec<(f64, &CueV2)> = cues
+                .iter()
+                .filter(|cue| cue.kind() == Some(cue_type))
+                .filter_map(|cue| Some((cue.start_ms()?, cue)))
+                .collect();
+            starts.sort_by(|a, b| a.0.total_cmp(&b.0));
+            starts
+        };
+        let region = match *self {
+            CueRegion::Between(from, to) => {
+                let start = from
+                    .and_then(|from| of_type(from).first().map(|(start, _)| *start))
+                    .unwrap_or(0.0);
+                let end = to.and_then(|to| {
+                    of_type(to)
+                        .into_iter()
+                        .map(|(end, _)| end)
+                        .find(|end| *end > start)
+                });
+                Region {
+                    start: ms(start),
+                    end: end.map(ms),
+                }
+            }
+            CueRegion::Loop => {
+                let (start, length) = of_type(CueType::Loop)
+                    .into_iter()
+                    .filter_map(|(start, cue)| Some((start, cue.length_ms()?)))
+                    .find(|(_, length)| *length > 0.0)?;
+                Region {
+                    start: ms(start),
+                    end: Some(ms(start + length)),
+                }
+            }
+        };
+        Some(region).filter(|region| !region.start.is_zero() || region.end.is_some())
+    }
+}
+
+/// Parses `loop`, or the types of the cues the region is between, e.g.
+/// `load:fade-out`, either of them being left out for that end of the track.
+impl FromStr for CueRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("loop") {
+            return Ok(CueRegion::Loop);
+        }
+        let (from, to) = s
+            .split_once(':')
+            .ok_or_else(|| format!("not loop nor two cue types: {}", s))?;
+        let cue_type = |name: &str| match name.trim() {
+            "" => Ok(None),
+            name => name.parse().map(Some),
+        };
+        Ok(CueRegion::Between(cue_type(from)?, cue_type(to)?))
+    }
+}
+
+/// How squashed a track is, in dB: the lower, the more it was compressed and
+/// limited.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Dynamics {
+    /// Peak-to-loudness ratio: the true peak less the integrated loudness.
+    pub plr: f32,
+    /// Peak-to-short-term loudness ratio: the true peak less the highest
+    /// short-term loudness, that of the loudest 3 seconds.
+    pub psr: f32,
+    /// Sample peak less the RMS level of the channels.
+    pub crest_factor: f32,
+}
+
+/// Silence at the edges of a file, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Silence {
+    pub leading: f64,
+    pub trailing: f64,
+}
+
+/// The loudness of the loudest section of a track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SectionLoudness {
+    /// How it was picked.
+    pub section: LoudestSection,
+    pub loudness: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StemLoudness {
+    pub name: String,
+    /// Minus infinity for stems that are all silence, when it is excluded.
+    pub integrated_loudness: f32,
+    pub true_peak: f32,
+    pub loudness_range: f32,
+}
+
+/// The loudness at a point of a file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoudnessPoint {
+    /// Seconds from the start of the audio.
+    pub time: f64,
+    /// Loudness of the last 400 ms in LUFS, minus infinity when silent.
+    pub momentary: f64,
+    /// Loudness of the last 3 s in LUFS, minus infinity when silent.
+    pub short_term: f64,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ComputedLoudness {
     pub integrated_loudness: f32,
     pub true_peak: f32,
+    /// Loudness range in LU, as EBU Tech 3342 defines it. Unknown for results
+    /// cached before it was measured.
+    pub loudness_range: Option<f32>,
+    /// Loudness of each stem, when asked for and the file is a STEM file.
+    pub stems: Option<Vec<StemLoudness>>,
+    /// Name of the decoder the file was decoded with, which is that of its
+    /// format for the built-in ones. Unknown for cached results.
+    pub format: Option<String>,
+    /// Number of samples per channel that were measured, once the encoder
+    /// delay and padding of gapless files were left out. Unknown for cached
+    /// results.
+    pub sample_count: Option<u64>,
+    /// Sample rate of the decoded audio. Unknown for cached results.
+    pub sample_rate: Option<u32>,
+    /// Number of channels of the decoded audio. Unknown for results cached
+    /// before it was.
+    pub channels: Option<u32>,
+    /// Momentary and short-term loudness at each hop, when asked for. Only
+    /// the master of STEM files has one.
+    pub timeline: Option<Vec<LoudnessPoint>>,
+    /// Loudness of the loudest section, when the gain is based on it.
+    pub section_loudness: Option<SectionLoudness>,
+    /// Silence at the edges, when asked to find it.
+    pub silence: Option<Silence>,
+    /// Peak to loudness ratios of the track. Unknown for results cached
+    /// before they were measured.
+    pub dynamics: Option<Dynamics>,
+    /// How the track holds up in mono, when asked for and it is stereo.
+    pub stereo: Option<Stereo>,
+    /// Energy of the track in each band, when asked for and it isn't silent.
+    pub band_energy: Option<BandEnergy>,
+    /// Samples over each level, when asked to count the overs.
+    pub peak_histogram: Option<PeakHistogram>,
+    /// Algorithm `loudness` was measured with.
+    pub algorithm: LoudnessAlgorithm,
+    /// Loudness the gain is derived from, unless it is that of the loudest
+    /// section: the integrated loudness with BS.1770.
+    pub loudness: f32,
 }
 
-pub fn scan_loudness(path: &str) -> Result<ComputedLoudness, String> {
-    let decode_result = match Path::new(path)
-        .extension()
-        .and_then(OsStr::to_str)
-        .unwrap_or("??")
-        .to_lowercase()
-        .as_str()
-    {
-        "ogg" => handle_audrey(path),
-        "wav" => handle_hound(path),
-        "flac" => handle_claxon(path),
-        "mp3" => handle_minimp3(path),
-        _ => Err(format!("unknown file type: {}", &path)),
-    };
+impl ComputedLoudness {
+    /// The loudness the gain is derived from with `basis`, when it was
+    /// measured.
+    pub fn gain_loudness(&self, basis: &GainBasis) -> Option<f32> {
+        match basis {
+            GainBasis::Integrated => Some(self.loudness),
+            GainBasis::LoudestSection(section) => self
+                .section_loudness
+                .filter(|measured| measured.section == *section)
+                .map(|measured| measured.loudness),
+        }
+    }
 
-    match decode_result {
-        Ok(decoded) => {
-            let mut ebu =
-                EbuR128::new(decoded.channels, decoded.rate, Mode::I | Mode::TRUE_PEAK).unwrap();
-            ebu.add_frames_f32(&decoded.data).unwrap();
-
-            // find max peak of all channels: the model has a single value for the peak
-            let mut max_peak = 0.0;
-            for i in 0..decoded.channels {
-                if max_peak < ebu.true_peak(i).unwrap() {
-                    max_peak = ebu.true_peak(i).unwrap();
-                }
+    /// Seconds of audio that were measured, when known.
+    pub fn duration(&self) -> Option<f64> {
+        match (self.sample_count, self.sample_rate) {
+            (Some(count), Some(rate)) if rate > 0 => Some(count as f64 / rate as f64),
+            _ => None,
+        }
+    }
+}
+
+/// The decoders that come with the tool, one for each of the formats it knows.
+struct BuiltinDecoder(AudioFormat);
+
+impl Decoder for BuiltinDecoder {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn extensions(&self) -> &[&str] {
+        self.0.extensions()
+    }
+
+    fn sniff(&self, header: &[u8]) -> bool {
+        self.0.matches(header)
+    }
+
+    fn decode(&self, path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+        self.decode_with(path, sink, false)
+    }
+
+    fn decode_tolerant(&self, path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+        self.decode_with(path, sink, true)
+    }
+}
+
+impl BuiltinDecoder {
+    fn decode_with(
+        &self,
+        path: &str,
+        sink: &mut dyn SampleSink,
+        tolerant: bool,
+    ) -> Result<(), AnalysisError> {
+        match self.0 {
+            AudioFormat::Ogg => handle_ogg(path, sink, tolerant),
+            AudioFormat::Wav => handle_wav(path, sink),
+            AudioFormat::Flac if tolerant => handle_claxon_tolerant(path, sink),
+            AudioFormat::Flac => handle_claxon(path, sink),
+            AudioFormat::Mp3 => handle_minimp3(path, sink, tolerant),
+            AudioFormat::Aiff => handle_aiff(path, sink),
+            AudioFormat::Mp4 => handle_mp4(path, sink),
+        }
+    }
+}
+
+pub fn builtin_decoders() -> Vec<Arc<dyn Decoder>> {
+    AudioFormat::ALL
+        .iter()
+        .map(|format| Arc::new(BuiltinDecoder(*format)) as Arc<dyn Decoder>)
+        .collect()
+}
+
+/// Decodes the file at `path` with the built-in decoders, handing its audio to
+/// `sink` in chunks as it goes. Returns the name of the decoder used.
+pub fn decode(path: &str, sink: &mut dyn SampleSink) -> Result<String, AnalysisError> {
+    DecoderRegistry::default()
+        .decode(path, sink)
+        .map(str::to_string)
+}
+
+/// Measures the master and each stem of a Traktor STEM file, decoding them all
+/// in the same pass.
+fn scan_stems(
+    path: &str,
+    options: &AnalysisOptions,
+    region: Option<Region>,
+) -> Result<ComputedLoudness, AnalysisError> {
+    let info = mp4::read(path)?;
+    let audio_tracks: Vec<usize> = info
+        .tracks
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.is_audio())
+        .map(|(i, _)| i)
+        .collect();
+    if audio_tracks.is_empty() {
+        return Err(AnalysisError::Decode(format!("no audio track: {}", &path)));
+    }
+
+    let mut meters: Vec<LoudnessMeter> = audio_tracks
+        .iter()
+        .map(|_| LoudnessMeter::new(options))
+        .collect();
+    match region {
+        Some(region) => {
+            let mut regions: Vec<RegionSink> = meters
+                .iter_mut()
+                .map(|meter| RegionSink::new(meter, region))
+                .collect();
+            let sinks = audio_tracks
+                .iter()
+                .zip(regions.iter_mut())
+                .map(|(index, region)| (*index, region as &mut dyn SampleSink))
+                .collect();
+            decode_mp4_tracks(path, &info, sinks)?;
+        }
+        None => {
+            let sinks = audio_tracks
+                .iter()
+                .zip(meters.iter_mut())
+                .map(|(index, meter)| (*index, meter as &mut dyn SampleSink))
+                .collect();
+            decode_mp4_tracks(path, &info, sinks)?;
+        }
+    }
+
+    let mut meters = meters.into_iter();
+    // there is at least one audio track
+    let master = meters.next().unwrap().finish(path)?;
+    let mut stems = Vec::new();
+    for (i, meter) in meters.enumerate() {
+        let name = info
+            .stem_names
+            .get(i)
+            .cloned()
+            .unwrap_or_else(|| format!("Stem {}", i + 1));
+        // stems that are all silence, such as an empty vocal one, are left
+        // out of the measurement without failing the file
+        if meter.only_silence() {
+            stems.push(StemLoudness {
+                name,
+                integrated_loudness: f32::NEG_INFINITY,
+                true_peak: 0.0,
+                loudness_range: 0.0,
+            });
+            continue;
+        }
+        let stem = meter.finish(path)?;
+        stems.push(StemLoudness {
+            name,
+            integrated_loudness: stem.integrated_loudness,
+            true_peak: stem.true_peak,
+            loudness_range: stem.loudness_range,
+        });
+    }
+    Ok(ComputedLoudness {
+        integrated_loudness: master.integrated_loudness,
+        true_peak: master.true_peak,
+        loudness_range: Some(master.loudness_range),
+        stems: Some(stems),
+        format: Some(AudioFormat::Mp4.name().to_string()),
+        sample_count: Some(master.sample_count),
+        sample_rate: Some(master.sample_rate),
+        channels: Some(master.channels),
+        timeline: master.timeline,
+        section_loudness: master.section_loudness,
+        silence: master.silence,
+        dynamics: Some(master.dynamics),
+        stereo: master.stereo,
+        band_energy: master.band_energy,
+        peak_histogram: master.peak_histogram,
+        algorithm: master.algorithm,
+        loudness: master.loudness,
+    })
+}
+
+pub fn scan_loudness(
+    path: &str,
+    options: &AnalysisOptions,
+) -> Result<ComputedLoudness, AnalysisError> {
+    scan_region(path, options, None)
+}
+
+/// Like `scan_loudness`, only measuring `region` of the file when there is one.
+pub fn scan_region(
+    path: &str,
+    options: &AnalysisOptions,
+    region: Option<Region>,
+) -> Result<ComputedLoudness, AnalysisError> {
+    if options.stems && is_stem_file(path) {
+        return scan_stems(path, options, region);
+    }
+
+    let decode = |decoder: &dyn Decoder, meter: &mut LoudnessMeter| {
+        let mut region_sink;
+        let sink: &mut dyn SampleSink = match region {
+            Some(region) => {
+                region_sink = RegionSink::new(meter, region);
+                &mut region_sink
             }
-            Ok(ComputedLoudness {
-                integrated_loudness: ebu.loudness_global().unwrap() as f32,
-                true_peak: max_peak as f32,
-            })
+            None => meter,
+        };
+        if options.tolerant {
+            decoder.decode_tolerant(path, sink)
+        } else {
+            decoder.decode(path, sink)
+        }
+    };
+    let decoder = options.decoders.find(path)?;
+    let mut meter = LoudnessMeter::new(options);
+    let mut format = decoder.name().to_string();
+    if let Err(e) = decode(decoder, &mut meter) {
+        // what the failing decoder measured is thrown away
+        match options.decoders.fallback() {
+            Some(fallback) if !options.decoders.is_fallback(decoder) => {
+                warn!("{}, trying {}", e, fallback.name());
+                meter = LoudnessMeter::new(options);
+                decode(fallback, &mut meter)?;
+                format = fallback.name().to_string();
+            }
+            _ => return Err(e),
         }
-        Err(e) => Err(e),
     }
+    let measurement = meter.finish(path)?;
+    Ok(ComputedLoudness {
+        integrated_loudness: measurement.integrated_loudness,
+        true_peak: measurement.true_peak,
+        loudness_range: Some(measurement.loudness_range),
+        stems: None,
+        format: Some(format),
+        sample_count: Some(measurement.sample_count),
+        sample_rate: Some(measurement.sample_rate),
+        channels: Some(measurement.channels),
+        timeline: measurement.timeline,
+        section_loudness: measurement.section_loudness,
+        silence: measurement.silence,
+        dynamics: Some(measurement.dynamics),
+        stereo: measurement.stereo,
+        band_energy: measurement.band_energy,
+        peak_histogram: measurement.peak_histogram,
+        algorithm: measurement.algorithm,
+        loudness: measurement.loudness,
+    })
+}
+
+/// Levels in dBTP samples are counted over once the gain is applied: 0dBTP,
+/// and the ceiling of the gain policy.
+fn over_thresholds(policy: &GainPolicy) -> Vec<f32> {
+    let mut thresholds = vec![0.0];
+    thresholds.extend(policy.ceiling.filter(|ceiling| *ceiling != 0.0));
+    thresholds
 }
 
 fn compute_and_update_model(
     loudness: &ComputedLoudness,
     target_loudness: f32,
+    options: &AnalysisOptions,
     entry: &mut Entry,
 ) -> AnalysisDifference {
     let peak = linear_to_db(loudness.true_peak);
-    let gain = loudness_to_gain(loudness.integrated_loudness, target_loudness);
+    let basis_loudness = loudness
+        .gain_loudness(&options.gain_basis)
+        .unwrap_or(loudness.loudness);
+    let target_gain = loudness_to_gain(basis_loudness, target_loudness);
+    let (gain, clamp, over_ceiling) = options.gain_policy.apply(target_gain, peak);
     let peak_after_gain = peak + gain;
 
     if peak_after_gain > 0.0 {
@@ -234,6 +2260,31 @@ fn compute_and_update_model(
         computed_analyzed_db: loudness.integrated_loudness as f64,
         computed_perceived_db: loudness.integrated_loudness as f64,
         computed_peak_db: peak as f64,
+        computed_loudness_range: loudness.loudness_range.map(f64::from),
+        computed_section_loudness: loudness.section_loudness.map(|s| f64::from(s.loudness)),
+        dynamics: loudness.dynamics,
+        stereo: loudness.stereo,
+        loses_level_in_mono: loudness
+            .stereo
+            .is_some_and(|stereo| stereo.mono_loudness_delta < -options.max_mono_loss),
+        band_energy: loudness.band_energy,
+        algorithm: loudness.algorithm.name(),
+        silence: loudness.silence,
+        stems: loudness.stems.clone(),
+        coverage: None,
+        clamp,
+        over_ceiling,
+        target_gain_db: clamp.map(|_| target_gain as f64),
+        overs: loudness
+            .peak_histogram
+            .as_ref()
+            .filter(|_| options.count_overs)
+            .map(|peaks| {
+                over_thresholds(&options.gain_policy)
+                    .into_iter()
+                    .map(|threshold| peaks.overs(gain, threshold))
+                    .collect()
+            }),
     };
 
     if entry.loudness.is_some() {
@@ -254,16 +2305,53 @@ fn compute_and_update_model(
     diff
 }
 
+/// Percentage of the duration Traktor has for `entry`, or for its `region`,
+/// that was measured, when both are known. Traktor's duration can be a bit
+/// longer than the audio of gapless files, hence the cap.
+fn coverage(loudness: &ComputedLoudness, entry: &Entry, region: Option<Region>) -> Option<f64> {
+    let play_time: f64 = entry.info.play_time_float.as_ref()?.parse().ok()?;
+    let expected = match region {
+        Some(region) => {
+            let end = region
+                .end
+                .map_or(play_time, |end| end.as_secs_f64().min(play_time));
+            end - region.start.as_secs_f64()
+        }
+        None => play_time,
+    };
+    if expected <= 0.0 {
+        return None;
+    }
+    Some((loudness.duration()? / expected * 100.0).min(100.0))
+}
+
+/// Text of a panic payload, which is a `&str` or a `String` unless the panic
+/// was raised with something else.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown".to_string(),
+        },
+    }
+}
+
+/// Analyses all entries of `collection`, returning those that failed.
 pub fn collection_analysis<T>(
     collection: &mut models::Nml,
     target_loudness: f32,
+    options: &AnalysisOptions,
     cache: Arc<Mutex<Cache>>,
     progress_callback: T,
     diff: &mut Vec<AnalysisDifference>,
-) where
+) -> Vec<AnalysisFailure>
+where
     T: Fn(&str) + Send + 'static + std::marker::Sync,
 {
     let locked_diff = Mutex::new(diff);
+    let mut failures = Vec::new();
+    let locked_failures = Mutex::new(&mut failures);
 
     collection
         .collection
@@ -287,14 +2375,48 @@ pub fn collection_analysis<T>(
             path.retain(|c| c != ':');
             path.push_str(&entry.location.file);
 
-            if let Some(audio_id) = &entry.audio_id {
-                let v = cache.lock().get(audio_id);
+            // the cache doesn't have the loudness of the stems
+            let needs_stems = options.stems && is_stem_file(&path);
+            // nor that of regions, which move with the cues
+            let region = options
+                .cue_region
+                .and_then(|cue_region| cue_region.region(entry.cue_v2.as_deref().unwrap_or(&[])));
+            // nor the silence, which its loudness may leave out, nor other
+            // weightings than K, which it doesn't tell apart, and it was
+            // measured with the layout of the files
+            let cacheable = region.is_none()
+                && options.silence_threshold.is_none()
+                && options.weighting == Weighting::K
+                && options.channel_layout.is_none();
+
+            if let Some(audio_id) = entry
+                .audio_id
+                .as_ref()
+                .filter(|_| !needs_stems && cacheable)
+            {
+                // results cached without the loudness the gain is based on,
+                // or without the band energy, the stereo image of stereo
+                // files or the peaks when they are asked for, are measured
+                // again
+                let v = cache.lock().get(audio_id).filter(|info| {
+                    info.loudness_info.algorithm == options.algorithm
+                        && info
+                            .loudness_info
+                            .gain_loudness(&options.gain_basis)
+                            .is_some()
+                        && (!options.band_energy || info.loudness_info.band_energy.is_some())
+                        && (!options.stereo
+                            || info.loudness_info.stereo.is_some()
+                            || info.loudness_info.channels.is_some_and(|c| c != 2))
+                        && (!options.count_overs || info.loudness_info.peak_histogram.is_some())
+                });
                 match v {
                     Some(info) => {
                         trace!("cache hit {} ", entry.location.file);
                         let diff = compute_and_update_model(
                             &info.loudness_info,
                             target_loudness,
+                            options,
                             &mut entry,
                         );
                         locked_diff.lock().push(diff);
@@ -307,14 +2429,33 @@ pub fn collection_analysis<T>(
                 }
             }
 
-            // open file and decode
-            match scan_loudness(&path) {
+            // open file and decode, a panic only failing this track
+            let scanned =
+                panic::catch_unwind(AssertUnwindSafe(|| scan_region(&path, options, region)))
+                    .unwrap_or_else(|payload| Err(AnalysisError::Panic(panic_message(payload))));
+            // skipping damaged parts can leave too little to go by
+            let coverage = match &scanned {
+                Ok(loudness) if options.tolerant => coverage(loudness, &entry, region),
+                _ => None,
+            };
+            let scanned = match coverage {
+                Some(coverage) if coverage < options.min_coverage => {
+                    Err(AnalysisError::Unreliable(coverage))
+                }
+                _ => scanned,
+            };
+            match scanned {
                 Ok(loudness) => {
-                    let diff = compute_and_update_model(&loudness, target_loudness, &mut entry);
+                    let mut diff =
+                        compute_and_update_model(&loudness, target_loudness, options, &mut entry);
+                    diff.coverage = coverage;
 
                     locked_diff.lock().push(diff);
 
-                    if let Some(audio_id) = &entry.audio_id {
+                    // results of files parts of which may have been skipped
+                    // aren't cached, for runs that aren't tolerant of that
+                    let whole = !options.tolerant || coverage.is_some_and(|c| c >= 100.0);
+                    if let Some(audio_id) = entry.audio_id.as_ref().filter(|_| cacheable && whole) {
                         cache.lock().store(AnalyzedFile {
                             audio_id: audio_id.clone(),
                             loudness_info: loudness,
@@ -324,7 +2465,80 @@ pub fn collection_analysis<T>(
                 }
                 Err(e) => {
                     error!("{}", e);
+                    locked_failures.lock().push(AnalysisFailure {
+                        path: entry.location.file.clone(),
+                        error: e,
+                    });
+                    progress_callback(&entry.location.file);
                 }
             }
         });
+
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(cue_type: i64, start: &str, length: &str) -> CueV2 {
+        CueV2 {
+            cue_type,
+            display_order: 0,
+            hotcue: -1,
+            length: length.to_string(),
+            name: "n.n.".to_string(),
+            repeats: -1,
+            start: start.to_string(),
+        }
+    }
+
+    #[test]
+    fn regions_between_cues() {
+        let ms = |ms| Duration::from_millis(ms);
+        let cues = [
+            cue(0, "100", "0"),
+            cue(2, "900", "0"),
+            cue(3, "250", "0"),
+            cue(2, "750", "0"),
+            cue(5, "400", "200"),
+        ];
+        let region = |cue_region: &str| cue_region.parse::<CueRegion>().unwrap().region(&cues);
+
+        assert_eq!(
+            region("load:fade-out"),
+            Some(Region {
+                start: ms(250),
+                end: Some(ms(750))
+            })
+        );
+        assert_eq!(
+            region("fade-out:"),
+            Some(Region {
+                start: ms(750),
+                end: None
+            })
+        );
+        assert_eq!(
+            region("LOOP"),
+            Some(Region {
+                start: ms(400),
+                end: Some(ms(600))
+            })
+        );
+        // no fade-in cue, and nothing else to leave out
+        assert_eq!(region("fade-in:"), None);
+        assert_eq!(
+            "load:outro".parse::<CueRegion>(),
+            Err("unknown cue type: outro".to_string())
+        );
+        assert_eq!(
+            ":load".parse::<CueRegion>(),
+            Ok(CueRegion::Between(None, Some(CueType::Load)))
+        );
+        assert_eq!(
+            "load".parse::<CueRegion>(),
+            Err("not loop nor two cue types: load".to_string())
+        );
+    }
 }
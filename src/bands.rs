@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use crate::weighting::{Biquad, Filter};
+
+/// Frequencies in Hz between the bands: sub, bass, low-mid, mid and high.
+const CROSSOVERS: [f64; 4] = [60.0, 250.0, 500.0, 4000.0];
+
+/// How the energy of a track is spread across the spectrum: the level of
+/// each band in dB, relative to that of all of them together.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BandEnergy {
+    /// Below 60Hz.
+    pub sub: f32,
+    /// From 60 to 250Hz.
+    pub bass: f32,
+    /// From 250 to 500Hz.
+    pub low_mid: f32,
+    /// From 500Hz to 4kHz.
+    pub mid: f32,
+    /// Above 4kHz.
+    pub high: f32,
+}
+
+/// Splits audio into bands with Linkwitz-Riley filters, two Butterworth ones
+/// in a row, summing the energy of each.
+pub struct BandAnalysis {
+    /// Filters of each band, for each channel.
+    filters: Vec<Vec<Vec<Filter>>>,
+    energy: [f64; 5],
+}
+
+impl BandAnalysis {
+    pub fn new(channels: u32, rate: u32) -> Self {
+        // filters above the Nyquist frequency are left out
+        let nyquist = rate as f64 / 2.0;
+        let linkwitz_riley = |biquad: fn(u32, f64, f64) -> Biquad, f0: f64| {
+            if f0 < nyquist {
+                vec![
+                    Filter::new(biquad(rate, f0, FRAC_1_SQRT_2)),
+                    Filter::new(biquad(rate, f0, FRAC_1_SQRT_2)),
+                ]
+            } else {
+                Vec::new()
+            }
+        };
+        let band = |index: usize| {
+            let mut filters = Vec::new();
+            if index > 0 {
+                filters.extend(linkwitz_riley(
+                    Biquad::unity_high_pass,
+                    CROSSOVERS[index - 1],
+                ));
+            }
+            if index < CROSSOVERS.len() {
+                filters.extend(linkwitz_riley(Biquad::low_pass, CROSSOVERS[index]));
+            }
+            filters
+        };
+        BandAnalysis {
+            filters: (0..channels)
+                .map(|_| (0..=CROSSOVERS.len()).map(band).collect())
+                .collect(),
+            energy: [0.0; 5],
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.filters.len()) {
+            for (sample, bands) in frame.iter().zip(self.filters.iter_mut()) {
+                for (energy, filters) in self.energy.iter_mut().zip(bands.iter_mut()) {
+                    let filtered = filters
+                        .iter_mut()
+                        .fold(*sample as f64, |sample, filter| filter.process(sample));
+                    *energy += filtered * filtered;
+                }
+            }
+        }
+    }
+
+    /// The band energy, none for silence.
+    pub fn finish(&self) -> Option<BandEnergy> {
+        let total: f64 = self.energy.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let level = |energy: f64| (10.0 * (energy / total).log10()) as f32;
+        let [sub, bass, low_mid, mid, high] = self.energy.map(level);
+        Some(BandEnergy {
+            sub,
+            bass,
+            low_mid,
+            mid,
+            high,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band_energy(frequency: f32) -> BandEnergy {
+        let samples: Vec<f32> = (0..48000)
+            .flat_map(|i| {
+                let sample = 0.5 * (i as f32 * frequency / 48000.0 * std::f32::consts::TAU).sin();
+                [sample, sample]
+            })
+            .collect();
+        let mut analysis = BandAnalysis::new(2, 48000);
+        analysis.add(&samples);
+        analysis.finish().unwrap()
+    }
+
+    #[test]
+    fn sines_are_in_their_band() {
+        let sub = band_energy(35.0);
+        assert!(sub.sub > -0.5);
+        assert!(sub.mid < -30.0);
+
+        let bass = band_energy(125.0);
+        assert!(bass.bass > -1.0);
+        assert!(bass.sub < bass.bass - 10.0);
+        assert!(bass.high < -30.0);
+
+        let mid = band_energy(1400.0);
+        assert!(mid.mid > -0.5);
+        assert!(mid.sub < -30.0);
+
+        let high = band_energy(10000.0);
+        assert!(high.high > -0.5);
+        assert!(high.bass < -30.0);
+    }
+
+    #[test]
+    fn silence_has_no_profile() {
+        let mut analysis = BandAnalysis::new(1, 48000);
+        analysis.add(&[0.0; 4800]);
+        assert_eq!(analysis.finish(), None);
+    }
+}
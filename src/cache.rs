@@ -1,10 +1,55 @@
-use crate::analysis::ComputedLoudness;
+use crate::analysis::{
+    BandEnergy, ComputedLoudness, Dynamics, LoudestSection, LoudnessAlgorithm, PeakHistogram,
+    SectionLoudness, Stereo,
+};
 use crate::error::AppError;
 use bitflags::*;
 use log::{error, info, trace};
 use rusqlite::*;
 use std::fs::remove_file;
 use std::path::Path;
+use std::time::Duration;
+
+/// Columns added to the tracks table since the first databases, and their
+/// type.
+const ADDED_COLUMNS: [(&str, &str); 21] = [
+    ("loudness_range", "REAL"),
+    ("section_length", "REAL"),
+    ("section_percentile", "REAL"),
+    ("section_loudness", "REAL"),
+    ("algorithm", "TEXT"),
+    ("algorithm_loudness", "REAL"),
+    ("plr", "REAL"),
+    ("psr", "REAL"),
+    ("crest_factor", "REAL"),
+    ("band_sub", "REAL"),
+    ("band_bass", "REAL"),
+    ("band_low_mid", "REAL"),
+    ("band_mid", "REAL"),
+    ("band_high", "REAL"),
+    ("channels", "INTEGER"),
+    ("correlation", "REAL"),
+    ("low_end_correlation", "REAL"),
+    ("mono_loudness_delta", "REAL"),
+    ("overs_rate", "INTEGER"),
+    ("overs_samples", "BLOB"),
+    ("overs_runs", "BLOB"),
+];
+
+/// Counts of a peak histogram as a blob, of 4 bytes little-endian each:
+/// more than a day of samples is taken as a day.
+fn counts_to_blob(counts: &[u64]) -> Vec<u8> {
+    counts
+        .iter()
+        .flat_map(|count| ((*count).min(u32::MAX as u64) as u32).to_le_bytes())
+        .collect()
+}
+
+fn blob_to_counts(blob: &[u8]) -> Vec<u64> {
+    blob.chunks_exact(4)
+        .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as u64)
+        .collect()
+}
 
 bitflags! {
     #[derive(Default)]
@@ -44,10 +89,38 @@ impl Cache {
                          id INTEGER PRIMARY KEY,
                          audio_id TEXT NOT NULL UNIQUE,
                          analyzed_db REAL,
-                         peak_db REAL
+                         peak_db REAL,
+                         loudness_range REAL,
+                         section_length REAL,
+                         section_percentile REAL,
+                         section_loudness REAL,
+                         algorithm TEXT,
+                         algorithm_loudness REAL,
+                         plr REAL,
+                         psr REAL,
+                         crest_factor REAL,
+                         band_sub REAL,
+                         band_bass REAL,
+                         band_low_mid REAL,
+                         band_mid REAL,
+                         band_high REAL,
+                         channels INTEGER,
+                         correlation REAL,
+                         low_end_correlation REAL,
+                         mono_loudness_delta REAL,
+                         overs_rate INTEGER,
+                         overs_samples BLOB,
+                         overs_runs BLOB
                      )",
                      (),
                 )?;
+                for (column, column_type) in ADDED_COLUMNS {
+                    let select = format!("SELECT {} FROM tracks", column);
+                    let add = format!("ALTER TABLE tracks ADD COLUMN {} {}", column, column_type);
+                    if db.prepare(&select).is_err() {
+                        db.execute(&add, ())?;
+                    }
+                }
 
                 Ok(Cache { db, policy })
             }
@@ -63,9 +136,9 @@ impl Cache {
             return None;
         }
 
-        let maybe_statement = self
-            .db
-            .prepare("SELECT audio_id, analyzed_db, peak_db FROM tracks where audio_id = ?1");
+        let maybe_statement = self.db.prepare(
+            "SELECT audio_id, analyzed_db, peak_db, loudness_range, section_length, section_percentile, section_loudness, algorithm, algorithm_loudness, plr, psr, crest_factor, band_sub, band_bass, band_low_mid, band_mid, band_high, channels, correlation, low_end_correlation, mono_loudness_delta, overs_rate, overs_samples, overs_runs FROM tracks where audio_id = ?1",
+        );
         let mut statement = match maybe_statement {
             Ok(s) => s,
             Err(_) => {
@@ -77,12 +150,109 @@ impl Cache {
                 if let Some(row) = rows.next().unwrap() {
                     let integrated_loudness = row.get::<_, f64>(1).unwrap() as f32;
                     let true_peak = row.get::<_, f64>(2).unwrap() as f32;
+                    let loudness_range = row.get::<_, Option<f64>>(3).unwrap().map(|r| r as f32);
+                    let section_loudness = match (
+                        row.get::<_, Option<f64>>(4).unwrap(),
+                        row.get::<_, Option<f64>>(5).unwrap(),
+                        row.get::<_, Option<f64>>(6).unwrap(),
+                    ) {
+                        (Some(length), Some(percentile), Some(loudness)) => Some(SectionLoudness {
+                            section: LoudestSection {
+                                length: Duration::from_secs_f64(length),
+                                percentile,
+                            },
+                            loudness: loudness as f32,
+                        }),
+                        _ => None,
+                    };
+                    // results cached before there were algorithms are BS.1770
+                    // ones, and those of unknown algorithms aren't used
+                    let algorithm = match row.get::<_, Option<String>>(7).unwrap() {
+                        Some(name) => name.parse().ok()?,
+                        None => LoudnessAlgorithm::Bs1770,
+                    };
+                    let loudness = row
+                        .get::<_, Option<f64>>(8)
+                        .unwrap()
+                        .map_or(integrated_loudness, |l| l as f32);
+                    let dynamics = match (
+                        row.get::<_, Option<f64>>(9).unwrap(),
+                        row.get::<_, Option<f64>>(10).unwrap(),
+                        row.get::<_, Option<f64>>(11).unwrap(),
+                    ) {
+                        (Some(plr), Some(psr), Some(crest_factor)) => Some(Dynamics {
+                            plr: plr as f32,
+                            psr: psr as f32,
+                            crest_factor: crest_factor as f32,
+                        }),
+                        _ => None,
+                    };
+                    let band_energy = match (
+                        row.get::<_, Option<f64>>(12).unwrap(),
+                        row.get::<_, Option<f64>>(13).unwrap(),
+                        row.get::<_, Option<f64>>(14).unwrap(),
+                        row.get::<_, Option<f64>>(15).unwrap(),
+                        row.get::<_, Option<f64>>(16).unwrap(),
+                    ) {
+                        (Some(sub), Some(bass), Some(low_mid), Some(mid), Some(high)) => {
+                            Some(BandEnergy {
+                                sub: sub as f32,
+                                bass: bass as f32,
+                                low_mid: low_mid as f32,
+                                mid: mid as f32,
+                                high: high as f32,
+                            })
+                        }
+                        _ => None,
+                    };
+                    let channels = row.get::<_, Option<u32>>(17).unwrap();
+                    let stereo = match (
+                        row.get::<_, Option<f64>>(18).unwrap(),
+                        row.get::<_, Option<f64>>(19).unwrap(),
+                        row.get::<_, Option<f64>>(20).unwrap(),
+                    ) {
+                        (Some(correlation), Some(low_end_correlation), Some(delta)) => {
+                            Some(Stereo {
+                                correlation: correlation as f32,
+                                low_end_correlation: low_end_correlation as f32,
+                                mono_loudness_delta: delta as f32,
+                            })
+                        }
+                        _ => None,
+                    };
+                    let peak_histogram = match (
+                        row.get::<_, Option<u32>>(21).unwrap(),
+                        row.get::<_, Option<Vec<u8>>>(22).unwrap(),
+                        row.get::<_, Option<Vec<u8>>>(23).unwrap(),
+                    ) {
+                        (Some(rate), Some(samples), Some(runs)) => Some(PeakHistogram {
+                            rate,
+                            samples: blob_to_counts(&samples),
+                            runs: blob_to_counts(&runs),
+                        }),
+                        _ => None,
+                    };
                     return Some({
                         AnalyzedFile {
                             audio_id: row.get(0).unwrap(),
                             loudness_info: ComputedLoudness {
                                 integrated_loudness,
                                 true_peak,
+                                loudness_range,
+                                stems: None,
+                                format: None,
+                                sample_count: None,
+                                sample_rate: None,
+                                channels,
+                                timeline: None,
+                                section_loudness,
+                                silence: None,
+                                dynamics,
+                                stereo,
+                                band_energy,
+                                peak_histogram,
+                                algorithm,
+                                loudness,
                             },
                         }
                     });
@@ -102,12 +272,40 @@ impl Cache {
         if self.policy.contains(CachePolicy::NO_WRITE) {
             return;
         }
+        let section = file.loudness_info.section_loudness;
+        let dynamics = file.loudness_info.dynamics;
+        let bands = file.loudness_info.band_energy;
+        let stereo = file.loudness_info.stereo;
+        let peaks = file.loudness_info.peak_histogram.as_ref();
+        // tracks measured again, e.g. for the loudness of another section,
+        // replace what was cached
         match self.db.execute(
-            "INSERT INTO tracks (audio_id, analyzed_db, peak_db) VALUES (?1, ?2, ?3)",
-            [
+            "INSERT OR REPLACE INTO tracks (audio_id, analyzed_db, peak_db, loudness_range, section_length, section_percentile, section_loudness, algorithm, algorithm_loudness, plr, psr, crest_factor, band_sub, band_bass, band_low_mid, band_mid, band_high, channels, correlation, low_end_correlation, mono_loudness_delta, overs_rate, overs_samples, overs_runs) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24)",
+            params![
                 &file.audio_id,
                 &file.loudness_info.integrated_loudness.to_string(),
                 &file.loudness_info.true_peak.to_string(),
+                file.loudness_info.loudness_range,
+                section.map(|s| s.section.length.as_secs_f64()),
+                section.map(|s| s.section.percentile),
+                section.map(|s| s.loudness),
+                file.loudness_info.algorithm.name(),
+                file.loudness_info.loudness,
+                dynamics.map(|d| d.plr),
+                dynamics.map(|d| d.psr),
+                dynamics.map(|d| d.crest_factor),
+                bands.map(|b| b.sub),
+                bands.map(|b| b.bass),
+                bands.map(|b| b.low_mid),
+                bands.map(|b| b.mid),
+                bands.map(|b| b.high),
+                file.loudness_info.channels,
+                stereo.map(|s| s.correlation),
+                stereo.map(|s| s.low_end_correlation),
+                stereo.map(|s| s.mono_loudness_delta),
+                peaks.map(|p| p.rate),
+                peaks.map(|p| counts_to_blob(&p.samples)),
+                peaks.map(|p| counts_to_blob(&p.runs)),
             ],
         ) {
             Ok(_) => {
@@ -119,3 +317,136 @@ impl Cache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn analyzed(loudness_range: Option<f32>) -> AnalyzedFile {
+        AnalyzedFile {
+            audio_id: "an audio id".to_string(),
+            loudness_info: ComputedLoudness {
+                integrated_loudness: -9.5,
+                true_peak: 0.5,
+                loudness_range,
+                stems: None,
+                format: None,
+                sample_count: None,
+                sample_rate: None,
+                channels: None,
+                timeline: None,
+                section_loudness: None,
+                silence: None,
+                dynamics: None,
+                stereo: None,
+                band_energy: None,
+                peak_histogram: None,
+                algorithm: LoudnessAlgorithm::Bs1770,
+                loudness: -9.5,
+            },
+        }
+    }
+
+    /// A result with everything that is cached, measured with another
+    /// algorithm than BS.1770.
+    fn fully_analyzed() -> AnalyzedFile {
+        let mut file = analyzed(Some(7.25));
+        file.loudness_info = ComputedLoudness {
+            channels: Some(2),
+            section_loudness: Some(SectionLoudness {
+                section: LoudestSection {
+                    length: Duration::from_secs(10),
+                    percentile: 95.0,
+                },
+                loudness: -7.5,
+            }),
+            dynamics: Some(Dynamics {
+                plr: 8.5,
+                psr: 6.25,
+                crest_factor: 9.75,
+            }),
+            stereo: Some(Stereo {
+                correlation: 0.75,
+                low_end_correlation: -0.5,
+                mono_loudness_delta: -4.25,
+            }),
+            band_energy: Some(BandEnergy {
+                sub: -9.5,
+                bass: -3.25,
+                low_mid: -8.0,
+                mid: -6.5,
+                high: -14.75,
+            }),
+            peak_histogram: Some(PeakHistogram {
+                rate: 44100,
+                samples: vec![44100, 20000, 35],
+                runs: vec![1, 400, 3],
+            }),
+            algorithm: LoudnessAlgorithm::ReplayGain1,
+            loudness: -7.25,
+            ..file.loudness_info
+        };
+        file
+    }
+
+    #[test]
+    fn results_are_cached() {
+        let directory = TempDir::new().unwrap();
+        let cache = Cache::new(&directory.path().join("cache.db"), CachePolicy::empty()).unwrap();
+        cache.store(fully_analyzed());
+        let cached = cache.get("an audio id").unwrap().loudness_info;
+        assert_eq!(cached, fully_analyzed().loudness_info);
+    }
+
+    #[test]
+    fn older_databases_get_the_loudness_range() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("cache.db");
+        let db = Connection::open(&path).unwrap();
+        db.execute(
+            "CREATE TABLE tracks (
+                 id INTEGER PRIMARY KEY,
+                 audio_id TEXT NOT NULL UNIQUE,
+                 analyzed_db REAL,
+                 peak_db REAL
+             )",
+            (),
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO tracks (audio_id, analyzed_db, peak_db) VALUES ('older', -8, 1)",
+            (),
+        )
+        .unwrap();
+        drop(db);
+
+        let cache = Cache::new(&path, CachePolicy::empty()).unwrap();
+        let older = cache.get("older").unwrap().loudness_info;
+        assert_eq!(older.loudness_range, None);
+        assert_eq!(older.algorithm, LoudnessAlgorithm::Bs1770);
+        assert_eq!(older.loudness, -8.0);
+        assert_eq!(older.dynamics, None);
+        // the columns added since hold all there is to cache
+        cache.store(fully_analyzed());
+        let newer = cache.get("an audio id").unwrap().loudness_info;
+        assert_eq!(newer, fully_analyzed().loudness_info);
+    }
+
+    #[test]
+    fn results_are_replaced() {
+        let directory = TempDir::new().unwrap();
+        let cache = Cache::new(&directory.path().join("cache.db"), CachePolicy::empty()).unwrap();
+        cache.store(analyzed(None));
+        // tracks measured again, e.g. for the loudness of a section
+        cache.store(fully_analyzed());
+        let cached = cache.get("an audio id").unwrap().loudness_info;
+        assert_eq!(cached, fully_analyzed().loudness_info);
+
+        // with nothing left of what was cached before
+        cache.store(analyzed(None));
+        let cached = cache.get("an audio id").unwrap().loudness_info;
+        assert_eq!(cached, analyzed(None).loudness_info);
+    }
+}
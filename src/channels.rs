@@ -0,0 +1,259 @@
+use ebur128::Channel;
+use std::str::FromStr;
+
+// speaker bits of a `WAVE_FORMAT_EXTENSIBLE` channel mask, in the order of the
+// channels that have them
+const SPEAKER_FRONT_LEFT: u32 = 0x1;
+const SPEAKER_FRONT_RIGHT: u32 = 0x2;
+const SPEAKER_FRONT_CENTER: u32 = 0x4;
+const SPEAKER_LOW_FREQUENCY: u32 = 0x8;
+const SPEAKER_BACK_LEFT: u32 = 0x10;
+const SPEAKER_BACK_RIGHT: u32 = 0x20;
+const SPEAKER_FRONT_LEFT_OF_CENTER: u32 = 0x40;
+const SPEAKER_FRONT_RIGHT_OF_CENTER: u32 = 0x80;
+const SPEAKER_BACK_CENTER: u32 = 0x100;
+const SPEAKER_SIDE_LEFT: u32 = 0x200;
+const SPEAKER_SIDE_RIGHT: u32 = 0x400;
+const SPEAKER_TOP_CENTER: u32 = 0x800;
+const SPEAKER_TOP_FRONT_LEFT: u32 = 0x1000;
+const SPEAKER_TOP_FRONT_CENTER: u32 = 0x2000;
+const SPEAKER_TOP_FRONT_RIGHT: u32 = 0x4000;
+const SPEAKER_TOP_BACK_LEFT: u32 = 0x8000;
+const SPEAKER_TOP_BACK_CENTER: u32 = 0x10000;
+const SPEAKER_TOP_BACK_RIGHT: u32 = 0x20000;
+
+/// Short names of the speakers, as ffmpeg has them.
+const SPEAKER_NAMES: [(&str, u32); 18] = [
+    ("FL", SPEAKER_FRONT_LEFT),
+    ("FR", SPEAKER_FRONT_RIGHT),
+    ("FC", SPEAKER_FRONT_CENTER),
+    ("LFE", SPEAKER_LOW_FREQUENCY),
+    ("BL", SPEAKER_BACK_LEFT),
+    ("BR", SPEAKER_BACK_RIGHT),
+    ("FLC", SPEAKER_FRONT_LEFT_OF_CENTER),
+    ("FRC", SPEAKER_FRONT_RIGHT_OF_CENTER),
+    ("BC", SPEAKER_BACK_CENTER),
+    ("SL", SPEAKER_SIDE_LEFT),
+    ("SR", SPEAKER_SIDE_RIGHT),
+    ("TC", SPEAKER_TOP_CENTER),
+    ("TFL", SPEAKER_TOP_FRONT_LEFT),
+    ("TFC", SPEAKER_TOP_FRONT_CENTER),
+    ("TFR", SPEAKER_TOP_FRONT_RIGHT),
+    ("TBL", SPEAKER_TOP_BACK_LEFT),
+    ("TBC", SPEAKER_TOP_BACK_CENTER),
+    ("TBR", SPEAKER_TOP_BACK_RIGHT),
+];
+
+/// The speaker each channel of a file feeds, in the order of the channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelLayout {
+    /// One bit of a `WAVE_FORMAT_EXTENSIBLE` channel mask per channel.
+    speakers: Vec<u32>,
+}
+
+impl ChannelLayout {
+    /// The layout of a `WAVE_FORMAT_EXTENSIBLE` channel mask: the channels are
+    /// those of the bits set, from the least significant one.
+    pub fn from_mask(mask: u32) -> Self {
+        ChannelLayout {
+            speakers: (0..32)
+                .map(|bit| 1 << bit)
+                .filter(|speaker| mask & speaker != 0)
+                .collect(),
+        }
+    }
+
+    /// The layout of FLAC files with `channels` channels, which is that of
+    /// WAV files with the default channel mask for that many channels.
+    pub fn flac(channels: u32) -> Option<Self> {
+        let mask = match channels {
+            1 => 0x4,
+            2 => 0x3,
+            3 => 0x7,
+            4 => 0x33,
+            5 => 0x37,
+            6 => 0x3f,
+            7 => 0x70f,
+            8 => 0x63f,
+            _ => return None,
+        };
+        Some(ChannelLayout::from_mask(mask))
+    }
+
+    /// The layout of Vorbis streams with `channels` channels, which Opus uses
+    /// as well: the center channel comes between the front ones, and the LFE
+    /// last.
+    pub fn vorbis(channels: u32) -> Option<Self> {
+        let speakers = match channels {
+            1 => vec![SPEAKER_FRONT_CENTER],
+            2 => vec![SPEAKER_FRONT_LEFT, SPEAKER_FRONT_RIGHT],
+            3 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_RIGHT,
+            ],
+            4 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_RIGHT,
+                SPEAKER_BACK_LEFT,
+                SPEAKER_BACK_RIGHT,
+            ],
+            5 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_RIGHT,
+                SPEAKER_BACK_LEFT,
+                SPEAKER_BACK_RIGHT,
+            ],
+            6 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_RIGHT,
+                SPEAKER_BACK_LEFT,
+                SPEAKER_BACK_RIGHT,
+                SPEAKER_LOW_FREQUENCY,
+            ],
+            7 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_RIGHT,
+                SPEAKER_SIDE_LEFT,
+                SPEAKER_SIDE_RIGHT,
+                SPEAKER_BACK_CENTER,
+                SPEAKER_LOW_FREQUENCY,
+            ],
+            8 => vec![
+                SPEAKER_FRONT_LEFT,
+                SPEAKER_FRONT_CENTER,
+                SPEAKER_FRONT_RIGHT,
+                SPEAKER_SIDE_LEFT,
+                SPEAKER_SIDE_RIGHT,
+                SPEAKER_BACK_LEFT,
+                SPEAKER_BACK_RIGHT,
+                SPEAKER_LOW_FREQUENCY,
+            ],
+            _ => return None,
+        };
+        Some(ChannelLayout { speakers })
+    }
+
+    /// Number of channels the layout has a speaker for.
+    pub fn channels(&self) -> u32 {
+        self.speakers.len() as u32
+    }
+
+    /// The BS.1770 position of each of `channels` channels. Back channels are
+    /// the surround ones of 5.1 layouts, which don't have side channels, and
+    /// the rear ones otherwise. Channels past those of the layout aren't
+    /// measured, and neither is the LFE.
+    pub fn channel_map(&self, channels: u32) -> Vec<Channel> {
+        let has_sides = self
+            .speakers
+            .iter()
+            .any(|s| *s == SPEAKER_SIDE_LEFT || *s == SPEAKER_SIDE_RIGHT);
+        let mut map: Vec<Channel> = self
+            .speakers
+            .iter()
+            .map(|speaker| match *speaker {
+                SPEAKER_FRONT_LEFT => Channel::Left,
+                SPEAKER_FRONT_RIGHT => Channel::Right,
+                SPEAKER_FRONT_CENTER => Channel::Center,
+                SPEAKER_LOW_FREQUENCY => Channel::Unused,
+                SPEAKER_BACK_LEFT if has_sides => Channel::Mp135,
+                SPEAKER_BACK_RIGHT if has_sides => Channel::Mm135,
+                SPEAKER_BACK_LEFT => Channel::LeftSurround,
+                SPEAKER_BACK_RIGHT => Channel::RightSurround,
+                SPEAKER_FRONT_LEFT_OF_CENTER => Channel::MpSC,
+                SPEAKER_FRONT_RIGHT_OF_CENTER => Channel::MmSC,
+                SPEAKER_BACK_CENTER => Channel::Mp180,
+                SPEAKER_SIDE_LEFT => Channel::Mp090,
+                SPEAKER_SIDE_RIGHT => Channel::Mm090,
+                SPEAKER_TOP_CENTER => Channel::Tp000,
+                SPEAKER_TOP_FRONT_LEFT => Channel::Up030,
+                SPEAKER_TOP_FRONT_CENTER => Channel::Up000,
+                SPEAKER_TOP_FRONT_RIGHT => Channel::Um030,
+                SPEAKER_TOP_BACK_LEFT => Channel::Up135,
+                SPEAKER_TOP_BACK_CENTER => Channel::Up180,
+                SPEAKER_TOP_BACK_RIGHT => Channel::Um135,
+                // reserved
+                _ => Channel::Unused,
+            })
+            .collect();
+        map.resize(channels as usize, Channel::Unused);
+        map
+    }
+}
+
+/// Parses speaker names separated by commas, e.g. `FL,FR,FC,LFE,BL,BR`.
+impl FromStr for ChannelLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let speakers = s
+            .split(',')
+            .map(|name| {
+                SPEAKER_NAMES
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name.trim()))
+                    .map(|(_, speaker)| *speaker)
+                    .ok_or_else(|| format!("unknown speaker: {}", name))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        Ok(ChannelLayout { speakers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conventions_of_the_formats() {
+        // 5.1: the LFE isn't measured, wherever it is
+        assert_eq!(
+            ChannelLayout::flac(6).unwrap().channel_map(6),
+            [
+                Channel::Left,
+                Channel::Right,
+                Channel::Center,
+                Channel::Unused,
+                Channel::LeftSurround,
+                Channel::RightSurround
+            ]
+        );
+        assert_eq!(
+            ChannelLayout::vorbis(6).unwrap().channel_map(6),
+            [
+                Channel::Left,
+                Channel::Center,
+                Channel::Right,
+                Channel::LeftSurround,
+                Channel::RightSurround,
+                Channel::Unused
+            ]
+        );
+        // 7.1: the back channels are behind the side ones
+        assert_eq!(
+            ChannelLayout::flac(8).unwrap().channel_map(8)[4..],
+            [
+                Channel::Mp135,
+                Channel::Mm135,
+                Channel::Mp090,
+                Channel::Mm090
+            ]
+        );
+        assert_eq!(ChannelLayout::flac(9), None);
+    }
+
+    #[test]
+    fn speaker_names() {
+        assert_eq!(
+            "fl, FR,FC,LFE,BL,BR".parse::<ChannelLayout>(),
+            Ok(ChannelLayout::from_mask(0x3f))
+        );
+        assert_eq!(
+            "FL,FR,SUB".parse::<ChannelLayout>(),
+            Err("unknown speaker: SUB".to_string())
+        );
+    }
+}
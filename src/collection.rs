@@ -1,4 +1,7 @@
-use crate::analysis::collection_analysis;
+use crate::analysis::{
+    collection_analysis, AnalysisOptions, Clamp, CommandDecoder, DecoderRegistry, GainBasis,
+    GainPolicy, LoudestSection, LoudnessAlgorithm, Overs, Weighting,
+};
 use crate::cache::*;
 use crate::error::AppError;
 use crate::models::AnalysisDifference;
@@ -17,8 +20,9 @@ use std::fs::create_dir_all;
 use std::fs::{copy, File};
 use std::io::Cursor;
 use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 
@@ -75,6 +79,68 @@ pub fn run(matches: &ArgMatches) -> Result<(), AppError> {
     let maybe_cache = Cache::new(&cache_file, flags)?;
     let cache = Arc::new(Mutex::new(maybe_cache));
 
+    let mut decoders = DecoderRegistry::default();
+    if let Some(template) = matches.get_one::<String>("decoder-command") {
+        decoders.set_fallback(Arc::new(CommandDecoder::new(template)?));
+    }
+    let options = AnalysisOptions {
+        stems: matches.get_flag("stems"),
+        decoders: Arc::new(decoders),
+        tolerant: matches.get_flag("tolerant"),
+        min_coverage: matches
+            .get_one::<String>("min-coverage")
+            .ok_or("no minimum coverage provided")?
+            .parse()?,
+        channel_layout: matches
+            .get_one::<String>("channel-layout")
+            .map(|layout| layout.parse())
+            .transpose()?,
+        gain_basis: gain_basis(matches)?,
+        cue_region: matches
+            .get_one::<String>("cue-region")
+            .map(|region| region.parse())
+            .transpose()?,
+        silence_threshold: matches
+            .get_one::<String>("silence-threshold")
+            .map(|threshold| threshold.parse())
+            .transpose()?,
+        exclude_silence: matches.get_flag("exclude-silence"),
+        algorithm: matches
+            .get_one::<String>("algorithm")
+            .ok_or("no loudness algorithm provided")?
+            .parse()?,
+        weighting: match matches.get_one::<String>("weighting-file") {
+            Some(path) => Weighting::from_file(Path::new(path))?,
+            None => matches
+                .get_one::<String>("weighting")
+                .ok_or("no weighting provided")?
+                .parse()?,
+        },
+        gain_policy: gain_policy(matches)?,
+        count_overs: matches.get_flag("count-overs"),
+        stereo: matches.get_flag("stereo"),
+        max_mono_loss: matches
+            .get_one::<String>("max-mono-loss")
+            .ok_or("no maximum mono loss provided")?
+            .parse()?,
+        band_energy: matches.get_flag("band-energy"),
+        ..Default::default()
+    };
+    // sections are measured as BS.1770 does
+    if options.gain_basis != GainBasis::Integrated && options.algorithm != LoudnessAlgorithm::Bs1770
+    {
+        return Err("the loudest section is only measured with the bs1770 algorithm".into());
+    }
+    // and K-weighted
+    if options.weighting != Weighting::K
+        && (options.algorithm != LoudnessAlgorithm::Bs1770
+            || options.gain_basis != GainBasis::Integrated)
+    {
+        return Err(
+            "other weightings than k only apply to the integrated loudness of bs1770".into(),
+        );
+    }
+
     let mut nml = deserialize_collection(input_path)?;
 
     let progress_bar = ProgressBar::new(nml.track_count());
@@ -105,9 +171,10 @@ pub fn run(matches: &ArgMatches) -> Result<(), AppError> {
         None
     };
 
-    collection_analysis(
+    let failures = collection_analysis(
         &mut nml,
         target_loudness,
+        &options,
         cache,
         progress_callback,
         &mut report_data,
@@ -115,6 +182,84 @@ pub fn run(matches: &ArgMatches) -> Result<(), AppError> {
 
     progress_bar_after.lock().finish();
 
+    if !failures.is_empty() {
+        warn!("{} tracks could not be analysed:", failures.len());
+        for failure in &failures {
+            warn!("\t{}: {}", failure.path, failure.error);
+        }
+    }
+
+    let clamps = |clamp: Clamp| {
+        report_data
+            .iter()
+            .filter(|difference| difference.clamp == Some(clamp))
+            .count()
+    };
+    let (ceiling, max_boost, max_cut) = (
+        clamps(Clamp::Ceiling),
+        clamps(Clamp::MaxBoost),
+        clamps(Clamp::MaxCut),
+    );
+    if ceiling + max_boost + max_cut > 0 {
+        warn!(
+            "{} tracks didn't get the gain reaching the target: {} limited by the ceiling, {} by the maximum boost, {} by the maximum cut",
+            ceiling + max_boost + max_cut,
+            ceiling,
+            max_boost,
+            max_cut
+        );
+    }
+    let over_ceiling = report_data
+        .iter()
+        .filter(|difference| difference.over_ceiling)
+        .count();
+    if over_ceiling > 0 {
+        warn!("{} tracks go over the ceiling", over_ceiling);
+    }
+
+    let overs: Vec<(&str, &Overs)> = report_data
+        .iter()
+        .filter_map(|difference| {
+            let overs = difference.overs.as_ref()?.first()?;
+            Some((difference.path.as_str(), overs)).filter(|_| overs.samples > 0)
+        })
+        .collect();
+    if !overs.is_empty() {
+        warn!(
+            "{} tracks go over 0 dBTP once their gain is applied:",
+            overs.len()
+        );
+        for (path, overs) in overs {
+            warn!(
+                "\t{}: {} samples over, {:.3}s in {} runs",
+                path, overs.samples, overs.duration, overs.runs
+            );
+        }
+    }
+
+    let mono_losses: Vec<&AnalysisDifference> = report_data
+        .iter()
+        .filter(|difference| difference.loses_level_in_mono)
+        .collect();
+    if !mono_losses.is_empty() {
+        warn!(
+            "{} tracks lose more than {} LU in mono:",
+            mono_losses.len(),
+            options.max_mono_loss
+        );
+        for difference in mono_losses {
+            // only stereo tracks are flagged
+            let stereo = difference.stereo.unwrap();
+            warn!(
+                "\t{}: {:.2} LU, correlation {:.2}, below 150Hz {:.2}",
+                difference.path,
+                stereo.mono_loudness_delta,
+                stereo.correlation,
+                stereo.low_end_correlation
+            );
+        }
+    }
+
     trace!("Finished - serializing collection");
 
     if difference_report_path.is_some() {
@@ -171,6 +316,55 @@ fn update_in_place(matches: &ArgMatches) -> bool {
     matches.contains_id("write")
 }
 
+fn gain_basis(matches: &ArgMatches) -> Result<GainBasis, AppError> {
+    if matches.get_one::<String>("gain-basis").map(String::as_str) != Some("loudest-section") {
+        return Ok(GainBasis::Integrated);
+    }
+    let length: f64 = matches
+        .get_one::<String>("section-length")
+        .ok_or("no section length provided")?
+        .parse()?;
+    let percentile: f64 = matches
+        .get_one::<String>("section-percentile")
+        .ok_or("no section percentile provided")?
+        .parse()?;
+    // the meter keeps a section of audio in memory
+    if !(0.4..=600.0).contains(&length) {
+        return Err("the section length must be between 0.4 and 600 seconds".into());
+    }
+    if !(percentile > 0.0 && percentile <= 100.0) {
+        return Err("the section percentile must be above 0 and at most 100".into());
+    }
+    Ok(GainBasis::LoudestSection(LoudestSection {
+        length: Duration::from_secs_f64(length),
+        percentile,
+    }))
+}
+
+fn gain_policy(matches: &ArgMatches) -> Result<GainPolicy, AppError> {
+    let db = |name: &str| -> Result<Option<f32>, AppError> {
+        Ok(matches
+            .get_one::<String>(name)
+            .map(|value| value.parse())
+            .transpose()?)
+    };
+    let policy = GainPolicy {
+        ceiling: db("ceiling")?,
+        max_boost: db("max-boost")?,
+        max_cut: db("max-cut")?,
+        priority: matches
+            .get_one::<String>("gain-priority")
+            .ok_or("no gain priority provided")?
+            .parse()?,
+    };
+    if policy.max_boost.is_some_and(|max_boost| max_boost < 0.0)
+        || policy.max_cut.is_some_and(|max_cut| max_cut < 0.0)
+    {
+        return Err("the maximum boost and cut must be positive numbers of dB".into());
+    }
+    Ok(policy)
+}
+
 fn deserialize_collection(path: &str) -> Result<Nml, AppError> {
     let file = File::open(path)?;
     let buf_reader = BufReader::new(file);
@@ -523,6 +717,8 @@ mod tests {
     use tempfile::TempDir;
 
     use super::*;
+    use crate::analysis::{AnalysisError, Decoder, GainPriority, SampleSink};
+    use crate::models::{AnalysisFailure, CueV2};
 
     #[test]
     fn serialization_roundtrip_on_a_1_element_collection() {
@@ -583,4 +779,355 @@ mod tests {
 
         assert_eq!(output.status.code().unwrap(), 0)
     }
+
+    /// Panics on whatever it is asked to decode.
+    struct PanickingDecoder;
+
+    impl Decoder for PanickingDecoder {
+        fn name(&self) -> &str {
+            "panicking"
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &["flac"]
+        }
+
+        fn decode(&self, _path: &str, _sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+            panic!("unexpected")
+        }
+    }
+
+    /// A collection of vectors from `tests/vectors`, written in `directory`.
+    fn collection_of_vectors(directory: &TempDir, files: &[&str]) -> Nml {
+        let entries: Vec<String> = files
+            .iter()
+            .map(|file| {
+                format!(
+                    "<ENTRY TITLE=\"{}\" ARTIST=\"sine\" AUDIO_ID=\"{}\"><LOCATION DIR=\"{}/:tests/:vectors/:\" FILE=\"{}\" VOLUME=\"\" VOLUMEID=\"\"></LOCATION><MODIFICATION_INFO AUTHOR_TYPE=\"user\"></MODIFICATION_INFO><INFO IMPORT_DATE=\"2020/1/11\"></INFO></ENTRY>",
+                    file,
+                    file,
+                    env!("CARGO_MANIFEST_DIR").replace('/', "/:"),
+                    file
+                )
+            })
+            .collect();
+        let path = directory.path().join("collection.nml");
+        std::fs::write(
+            &path,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?><NML VERSION=\"19\"><HEAD COMPANY=\"www.native-instruments.com\" PROGRAM=\"Traktor\"></HEAD><MUSICFOLDERS></MUSICFOLDERS><COLLECTION ENTRIES=\"{}\">{}</COLLECTION></NML>",
+                files.len(),
+                entries.concat()
+            ),
+        )
+        .unwrap();
+        deserialize_collection(path.to_str().unwrap()).unwrap()
+    }
+
+    fn analyse_without_cache(
+        directory: &TempDir,
+        nml: &mut Nml,
+        options: &AnalysisOptions,
+    ) -> (Vec<AnalysisDifference>, Vec<AnalysisFailure>) {
+        analyse_with_cache_policy(
+            directory,
+            nml,
+            options,
+            CachePolicy::NO_READ | CachePolicy::NO_WRITE,
+        )
+    }
+
+    fn analyse_with_cache(
+        directory: &TempDir,
+        nml: &mut Nml,
+        options: &AnalysisOptions,
+    ) -> (Vec<AnalysisDifference>, Vec<AnalysisFailure>) {
+        analyse_with_cache_policy(directory, nml, options, CachePolicy::empty())
+    }
+
+    fn analyse_with_cache_policy(
+        directory: &TempDir,
+        nml: &mut Nml,
+        options: &AnalysisOptions,
+        policy: CachePolicy,
+    ) -> (Vec<AnalysisDifference>, Vec<AnalysisFailure>) {
+        let cache = Cache::new(&directory.path().join("cache.db"), policy).unwrap();
+        let mut differences = Vec::new();
+        let failures = collection_analysis(
+            nml,
+            -14.0,
+            options,
+            Arc::new(Mutex::new(cache)),
+            |_| {},
+            &mut differences,
+        );
+        (differences, failures)
+    }
+
+    /// Makes the results cached in `directory` 20dB quieter than they are,
+    /// to tell when they are used.
+    fn make_the_cache_quieter(directory: &TempDir) {
+        let db = rusqlite::Connection::open(directory.path().join("cache.db")).unwrap();
+        db.execute(
+            "UPDATE tracks SET analyzed_db = analyzed_db - 20, algorithm_loudness = algorithm_loudness - 20",
+            (),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn results_are_cached_for_the_layout_of_the_files() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav"]);
+        let (measured, _) = analyse_with_cache(&directory, &mut nml, &Default::default());
+        let measured = measured[0].computed_analyzed_db;
+        make_the_cache_quieter(&directory);
+        let (cached, _) = analyse_with_cache(&directory, &mut nml, &Default::default());
+        assert!((cached[0].computed_analyzed_db - (measured - 20.0)).abs() < 0.01);
+
+        let options = AnalysisOptions {
+            channel_layout: Some("FL,FR".parse().unwrap()),
+            ..Default::default()
+        };
+        let (differences, _) = analyse_with_cache(&directory, &mut nml, &options);
+        assert!((differences[0].computed_analyzed_db - measured).abs() < 0.01);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn the_stereo_image_is_cached() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav"]);
+        let options = AnalysisOptions {
+            stereo: true,
+            ..Default::default()
+        };
+        // cached without it, the track is measured again
+        analyse_with_cache(&directory, &mut nml, &Default::default());
+        make_the_cache_quieter(&directory);
+        let (measured, _) = analyse_with_cache(&directory, &mut nml, &options);
+        assert!(measured[0].stereo.is_some());
+
+        make_the_cache_quieter(&directory);
+        let (cached, _) = analyse_with_cache(&directory, &mut nml, &options);
+        let quieter = measured[0].computed_analyzed_db - 20.0;
+        assert!((cached[0].computed_analyzed_db - quieter).abs() < 0.01);
+        assert_eq!(cached[0].stereo, measured[0].stereo);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn a_panic_only_fails_its_track() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav", "sine-440-16.flac"]);
+
+        let mut decoders = DecoderRegistry::default();
+        decoders.register(Arc::new(PanickingDecoder));
+        decoders.prefer("panicking").unwrap();
+        let options = AnalysisOptions {
+            decoders: Arc::new(decoders),
+            ..Default::default()
+        };
+        let (differences, failures) = analyse_without_cache(&directory, &mut nml, &options);
+
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].path, "sine-440-16.wav");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, "sine-440-16.flac");
+        assert_eq!(
+            failures[0].error,
+            AnalysisError::Panic("unexpected".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tracks_measured_too_little_keep_their_gain() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav", "sine-440-16.flac"]);
+        // both vectors last one second
+        for (entry, duration) in nml.collection.entries.iter().zip(["1.000000", "2.000000"]) {
+            entry.lock().info.play_time_float = Some(duration.to_string());
+        }
+
+        let options = AnalysisOptions {
+            tolerant: true,
+            ..Default::default()
+        };
+        let (differences, failures) = analyse_without_cache(&directory, &mut nml, &options);
+
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].path, "sine-440-16.wav");
+        assert_eq!(differences[0].coverage, Some(100.0));
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, "sine-440-16.flac");
+        assert_eq!(failures[0].error, AnalysisError::Unreliable(50.0));
+        assert!(nml.collection.entries[1].lock().loudness.is_none());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tracks_measured_in_part_arent_cached() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav", "sine-440-16.flac"]);
+        // both vectors last one second, the first one measured at 95%
+        for (entry, duration) in nml.collection.entries.iter().zip(["1.050000", "1.000000"]) {
+            entry.lock().info.play_time_float = Some(duration.to_string());
+        }
+        let options = AnalysisOptions {
+            tolerant: true,
+            ..Default::default()
+        };
+        let (differences, failures) = analyse_with_cache(&directory, &mut nml, &options);
+        assert!(failures.is_empty());
+        let loudness = |differences: &[AnalysisDifference], path: &str| {
+            let difference = differences.iter().find(|d| d.path == path).unwrap();
+            difference.computed_analyzed_db
+        };
+        let measured = loudness(&differences, "sine-440-16.wav");
+
+        // only the one measured whole is taken from the cache
+        make_the_cache_quieter(&directory);
+        let (differences, _) =
+            analyse_with_cache(&directory, &mut nml, &AnalysisOptions::default());
+        assert_eq!(loudness(&differences, "sine-440-16.wav"), measured);
+        assert!((loudness(&differences, "sine-440-16.flac") - measured + 20.0).abs() < 0.1);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn gains_are_limited_by_the_policy() {
+        let directory = TempDir::new().unwrap();
+        // -3.72 LUFS, with a true peak of -3.03 dBTP: -10.28 dB to reach -14
+        let analyse = |gain_policy| {
+            let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav"]);
+            let options = AnalysisOptions {
+                gain_policy,
+                ..Default::default()
+            };
+            let (mut differences, _) = analyse_without_cache(&directory, &mut nml, &options);
+            let gain = nml.collection.entries[0]
+                .lock()
+                .loudness
+                .as_ref()
+                .unwrap()
+                .analyzed_db
+                .unwrap();
+            (gain, differences.remove(0))
+        };
+
+        let (gain, difference) = analyse(GainPolicy::default());
+        assert!((gain + 10.28).abs() < 0.01);
+        assert_eq!(difference.clamp, None);
+        assert_eq!(difference.target_gain_db, None);
+
+        let (gain, difference) = analyse(GainPolicy {
+            max_cut: Some(6.0),
+            ..Default::default()
+        });
+        assert_eq!(gain, -6.0);
+        assert_eq!(difference.clamp, Some(Clamp::MaxCut));
+        assert!((difference.target_gain_db.unwrap() + 10.28).abs() < 0.01);
+
+        let ceiling = GainPolicy {
+            ceiling: Some(-15.0),
+            ..Default::default()
+        };
+        let (gain, difference) = analyse(ceiling);
+        assert!((gain + 11.97).abs() < 0.01);
+        assert_eq!(difference.clamp, Some(Clamp::Ceiling));
+        assert!(!difference.over_ceiling);
+
+        let (gain, difference) = analyse(GainPolicy {
+            priority: GainPriority::Target,
+            ..ceiling
+        });
+        assert!((gain + 10.28).abs() < 0.01);
+        assert_eq!(difference.clamp, None);
+        assert!(difference.over_ceiling);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn overs_are_counted_with_the_gain() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav"]);
+        let options = AnalysisOptions {
+            gain_policy: GainPolicy {
+                ceiling: Some(-1.0),
+                priority: GainPriority::Target,
+                ..Default::default()
+            },
+            count_overs: true,
+            ..Default::default()
+        };
+        let cache = Cache::new(
+            &directory.path().join("cache.db"),
+            CachePolicy::NO_READ | CachePolicy::NO_WRITE,
+        )
+        .unwrap();
+        let mut differences = Vec::new();
+        // +3.72 dB, taking the true peak to +0.69 dBTP
+        collection_analysis(
+            &mut nml,
+            0.0,
+            &options,
+            Arc::new(Mutex::new(cache)),
+            |_| {},
+            &mut differences,
+        );
+
+        let overs = differences[0].overs.as_ref().unwrap();
+        assert_eq!(overs.len(), 2);
+        assert_eq!((overs[0].threshold, overs[1].threshold), (0.0, -1.0));
+        assert!(overs[0].samples > 0);
+        assert!(overs[0].runs > 400);
+        assert!(overs[1].samples > overs[0].samples);
+
+        // nothing goes over at -14 LUFS
+        let (differences, _) = analyse_with_cache(&directory, &mut nml, &options);
+        assert_eq!(differences[0].overs.as_ref().unwrap()[0].samples, 0);
+
+        // counted again from the cache, 20 dB louder
+        make_the_cache_quieter(&directory);
+        let (differences, _) = analyse_with_cache(&directory, &mut nml, &options);
+        assert!(differences[0].overs.as_ref().unwrap()[0].samples > 0);
+    }
+
+    fn cue(cue_type: i64, start: &str, length: &str) -> CueV2 {
+        CueV2 {
+            cue_type,
+            display_order: 0,
+            hotcue: -1,
+            length: length.to_string(),
+            name: "n.n.".to_string(),
+            repeats: -1,
+            start: start.to_string(),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn only_the_region_between_cues_is_measured() {
+        let directory = TempDir::new().unwrap();
+        let mut nml = collection_of_vectors(&directory, &["sine-440-16.wav"]);
+        // the vector lasts one second, which is only half of what Traktor
+        // has, but all of the region
+        {
+            let mut entry = nml.collection.entries[0].lock();
+            entry.info.play_time_float = Some("2.000000".to_string());
+            entry.cue_v2 = Some(vec![cue(3, "250", "0"), cue(2, "750", "0")]);
+        }
+
+        let options = AnalysisOptions {
+            tolerant: true,
+            cue_region: Some("load:fade-out".parse().unwrap()),
+            ..Default::default()
+        };
+        let (differences, failures) = analyse_without_cache(&directory, &mut nml, &options);
+
+        assert!(failures.is_empty());
+        assert_eq!(differences[0].coverage, Some(100.0));
+    }
 }
@@ -0,0 +1,168 @@
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::analysis::{SampleSink, CHUNK_FRAMES};
+use crate::decoder::Decoder;
+use crate::error::AnalysisError;
+
+/// Decodes files by running a command that writes their audio to its standard
+/// output, as interleaved 32 bits little-endian floats, e.g. ffmpeg:
+///
+/// `ffmpeg -v error -i {input} -f f32le -ac {channels=2} -ar {rate=48000} -`
+///
+/// `{input}` is replaced by the path of the file to decode, and the number of
+/// channels and the sample rate of the output are declared with
+/// `{channels=N}` and `{rate=N}`, which are replaced by `N`. The template is
+/// split on whitespace and run without a shell.
+#[derive(Debug)]
+pub struct CommandDecoder {
+    program: String,
+    args: Vec<String>,
+    channels: u32,
+    rate: u32,
+}
+
+const INPUT_PLACEHOLDER: &str = "{input}";
+
+/// Finds the `{key=N}` declaration in `args`, replacing it with `N`.
+fn take_declaration(args: &mut [String], key: &str) -> Result<u32, String> {
+    let prefix = format!("{{{}=", key);
+    for arg in args.iter_mut() {
+        let start = match arg.find(&prefix) {
+            Some(start) => start,
+            None => continue,
+        };
+        let end = match arg[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let value = &arg[start + prefix.len()..end];
+        let parsed = match value.parse::<u32>() {
+            Ok(parsed) if parsed > 0 => parsed,
+            _ => return Err(format!("invalid {} in decoder command: {}", key, value)),
+        };
+        *arg = format!("{}{}{}", &arg[..start], value, &arg[end + 1..]);
+        return Ok(parsed);
+    }
+    Err(format!("decoder command must declare {{{}=N}}", key))
+}
+
+impl CommandDecoder {
+    pub fn new(template: &str) -> Result<Self, String> {
+        let mut words: Vec<String> = template.split_whitespace().map(str::to_string).collect();
+        if words.is_empty() {
+            return Err("empty decoder command".to_string());
+        }
+        if !words.iter().any(|w| w.contains(INPUT_PLACEHOLDER)) {
+            return Err(format!(
+                "decoder command must contain {}",
+                INPUT_PLACEHOLDER
+            ));
+        }
+        let channels = take_declaration(&mut words, "channels")?;
+        let rate = take_declaration(&mut words, "rate")?;
+        let program = words.remove(0);
+        Ok(CommandDecoder {
+            program,
+            args: words,
+            channels,
+            rate,
+        })
+    }
+}
+
+impl Decoder for CommandDecoder {
+    fn name(&self) -> &str {
+        &self.program
+    }
+
+    /// Not picked by extension: the command is the fallback for whatever the
+    /// other decoders don't handle.
+    fn extensions(&self) -> &[&str] {
+        &[]
+    }
+
+    fn decode(&self, path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+        let mut child = match Command::new(&self.program)
+            .args(self.args.iter().map(|a| a.replace(INPUT_PLACEHOLDER, path)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(AnalysisError::Decode(format!(
+                    "decoder command not found: {}",
+                    &self.program
+                )))
+            }
+            Err(e) => return Err(AnalysisError::Decode(format!("{}: {}", &self.program, e))),
+        };
+        // read on the side, the command could otherwise block on a full pipe
+        let mut stderr = child.stderr.take().unwrap();
+        let errors = thread::spawn(move || {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+
+        let mut stdout = child.stdout.take().unwrap();
+        let result = stream_samples(&mut stdout, self.channels, self.rate, sink);
+        // stop the command if the sink gave up
+        if result.is_err() {
+            let _ = child.kill();
+        }
+        drop(stdout);
+        let status = child.wait()?;
+        let errors = errors.join().unwrap_or_default();
+        result?;
+        if !status.success() {
+            return Err(AnalysisError::Decode(format!(
+                "decoder command failed: {} ({})",
+                &path,
+                errors.trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Hands the raw floats of `reader` to `sink`, a chunk at a time.
+fn stream_samples(
+    reader: &mut dyn Read,
+    channels: u32,
+    rate: u32,
+    sink: &mut dyn SampleSink,
+) -> Result<(), AnalysisError> {
+    sink.format(channels, rate)?;
+    let frame_size = channels as usize * 4;
+    let mut buffer = vec![0; CHUNK_FRAMES * frame_size];
+    let mut samples = Vec::with_capacity(CHUNK_FRAMES * channels as usize);
+    // bytes left over from the previous read, not making a whole frame
+    let mut pending = 0;
+    loop {
+        let read = match reader.read(&mut buffer[pending..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let available = pending + read;
+        let whole = available - available % frame_size;
+        samples.clear();
+        samples.extend(
+            buffer[..whole]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        );
+        if !samples.is_empty() {
+            sink.write(&samples)?;
+        }
+        buffer.copy_within(whole..available, 0);
+        pending = available - whole;
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::analysis::{builtin_decoders, SampleSink};
+use crate::error::AnalysisError;
+use crate::format;
+
+/// Something that can decode audio files of a format.
+///
+/// Decoders are looked up in a `DecoderRegistry`, first by the content of the
+/// file, then by its extension.
+pub trait Decoder: Send + Sync {
+    /// Name of the format or of the decoder, as shown to users.
+    fn name(&self) -> &str;
+
+    /// Lowercase extensions of the files this decoder handles.
+    fn extensions(&self) -> &[&str];
+
+    /// Whether this decoder recognises `header`, the first bytes of a file,
+    /// following its ID3v2 tag if it has one. Decoders that can't tell from
+    /// the content are only picked by extension.
+    fn sniff(&self, _header: &[u8]) -> bool {
+        false
+    }
+
+    /// Decodes the file at `path`, handing its audio to `sink` in chunks.
+    fn decode(&self, path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError>;
+
+    /// Decodes the file at `path` like `decode`, but skips the damaged parts
+    /// of it instead of failing. Decoders that can't get past those decode it
+    /// like `decode` does.
+    fn decode_tolerant(&self, path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+        self.decode(path, sink)
+    }
+}
+
+/// The decoders to choose from.
+#[derive(Clone)]
+pub struct DecoderRegistry {
+    decoders: Vec<Arc<dyn Decoder>>,
+    /// Name of the decoder to use whenever it can handle a file.
+    preferred: Option<String>,
+    /// Decoder for the files that none of the others can handle.
+    fallback: Option<Arc<dyn Decoder>>,
+}
+
+impl Default for DecoderRegistry {
+    /// A registry with the built-in decoders.
+    fn default() -> Self {
+        let mut registry = DecoderRegistry::empty();
+        for decoder in builtin_decoders() {
+            registry.register(decoder);
+        }
+        registry
+    }
+}
+
+impl fmt::Debug for DecoderRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.decoders.iter().map(|d| d.name()))
+            .finish()
+    }
+}
+
+impl DecoderRegistry {
+    pub fn empty() -> Self {
+        DecoderRegistry {
+            decoders: Vec::new(),
+            preferred: None,
+            fallback: None,
+        }
+    }
+
+    /// Adds a decoder. Between decoders recognising a file in the same way,
+    /// those registered first are chosen.
+    pub fn register(&mut self, decoder: Arc<dyn Decoder>) {
+        self.decoders.push(decoder);
+    }
+
+    /// Makes the decoder called `name` the one chosen whenever it can handle a
+    /// file, by its content or by its extension.
+    pub fn prefer(&mut self, name: &str) -> Result<(), String> {
+        if !self.decoders.iter().any(|d| d.name() == name) {
+            return Err(format!("unknown decoder: {}", name));
+        }
+        self.preferred = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Sets the decoder used for the files that no registered decoder handles,
+    /// or that they fail to decode.
+    pub fn set_fallback(&mut self, decoder: Arc<dyn Decoder>) {
+        self.fallback = Some(decoder);
+    }
+
+    pub fn fallback(&self) -> Option<&dyn Decoder> {
+        self.fallback.as_deref()
+    }
+
+    /// The registered decoders, in the order they were registered.
+    pub fn decoders(&self) -> impl Iterator<Item = &dyn Decoder> {
+        self.decoders.iter().map(|d| d.as_ref())
+    }
+
+    /// All the extensions handled by the registered decoders, without duplicates.
+    pub fn supported_extensions(&self) -> Vec<&str> {
+        let mut extensions: Vec<&str> = Vec::new();
+        for extension in self.decoders().flat_map(|d| d.extensions().iter().copied()) {
+            if !extensions.contains(&extension) {
+                extensions.push(extension);
+            }
+        }
+        extensions
+    }
+
+    /// Decoders that can handle the file at `path`, the best first: the
+    /// preferred decoder, then those that recognise its content, then those
+    /// handling its extension. A file with an ID3v2 tag and nothing
+    /// recognisable after it is taken for an MP3.
+    pub fn candidates(&self, path: &str) -> Result<Vec<&dyn Decoder>, AnalysisError> {
+        let header = format::read_header(path)?;
+        let extension = format::extension(path);
+        let handles =
+            |decoder: &dyn Decoder, extension: &str| decoder.extensions().contains(&extension);
+
+        let mut candidates: Vec<&dyn Decoder> =
+            self.decoders().filter(|d| d.sniff(&header.bytes)).collect();
+        if candidates.is_empty() && header.id3v2 {
+            candidates.extend(self.decoders().filter(|d| handles(*d, "mp3")));
+        }
+        if let Some(extension) = extension {
+            for decoder in self.decoders().filter(|d| handles(*d, &extension)) {
+                if !candidates.iter().any(|c| std::ptr::addr_eq(*c, decoder)) {
+                    candidates.push(decoder);
+                }
+            }
+        }
+        if let Some(preferred) = &self.preferred {
+            // sorting is stable: the order is otherwise kept
+            candidates.sort_by_key(|d| d.name() != preferred);
+        }
+        Ok(candidates)
+    }
+
+    /// The decoder to use for the file at `path`, the fallback one if none of
+    /// the others handles it.
+    pub fn find(&self, path: &str) -> Result<&dyn Decoder, AnalysisError> {
+        match self.candidates(path)?.first().copied().or(self.fallback()) {
+            Some(decoder) => Ok(decoder),
+            None => Err(AnalysisError::UnknownFormat(format!(
+                "unknown file type: {}",
+                &path
+            ))),
+        }
+    }
+
+    /// Whether `decoder` is the fallback decoder.
+    pub fn is_fallback(&self, decoder: &dyn Decoder) -> bool {
+        self.fallback()
+            .is_some_and(|fallback| std::ptr::addr_eq(fallback, decoder))
+    }
+
+    /// Decodes the file at `path` with the decoder it is best handled by,
+    /// handing its audio to `sink`. Returns the name of that decoder.
+    ///
+    /// The fallback decoder is not tried when that decoder fails, as `sink`
+    /// already has some of the audio: see `scan_loudness` for that.
+    pub fn decode(&self, path: &str, sink: &mut dyn SampleSink) -> Result<&str, AnalysisError> {
+        let decoder = self.find(path)?;
+        decoder.decode(path, sink)?;
+        Ok(decoder.name())
+    }
+}
@@ -64,8 +64,63 @@ impl From<rusqlite::Error> for AppError {
 //     }
 // }
 
+impl From<String> for AppError {
+    fn from(error: String) -> AppError {
+        AppError::GenericError(error)
+    }
+}
+
 impl From<&str> for AppError {
     fn from(error: &str) -> AppError {
         AppError::GenericError(error.to_string())
     }
 }
+
+/// Why a file couldn't be analysed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnalysisError {
+    /// The file can't be opened or read.
+    Io(String),
+    /// No decoder handles the file.
+    UnknownFormat(String),
+    /// The file isn't valid, or uses a variant of its format that isn't
+    /// supported.
+    Decode(String),
+    /// The decoded audio can't be measured.
+    Measure(String),
+    /// Analysing the file panicked.
+    Panic(String),
+    /// Too little of the file could be decoded to trust its loudness: the
+    /// percentage of its duration that was measured.
+    Unreliable(f64),
+}
+
+impl std::fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AnalysisError::Io(ref message)
+            | AnalysisError::UnknownFormat(ref message)
+            | AnalysisError::Decode(ref message)
+            | AnalysisError::Measure(ref message) => f.write_str(message),
+            AnalysisError::Panic(ref message) => write!(f, "panic: {}", message),
+            AnalysisError::Unreliable(coverage) => {
+                write!(f, "only {:.1}% of the track could be measured", coverage)
+            }
+        }
+    }
+}
+
+impl Error for AnalysisError {}
+
+/// Decoding libraries report their errors as text.
+impl From<String> for AnalysisError {
+    fn from(error: String) -> AnalysisError {
+        AnalysisError::Decode(error)
+    }
+}
+
+impl From<std::io::Error> for AnalysisError {
+    fn from(error: std::io::Error) -> AnalysisError {
+        AnalysisError::Io(error.to_string())
+    }
+}
@@ -0,0 +1,168 @@
+use crate::error::AnalysisError;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+/// The audio file formats that can be decoded, by container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    /// Vorbis, Opus or FLAC in an Ogg container.
+    Ogg,
+    Mp3,
+    Aiff,
+    /// AAC or ALAC in an MP4 container.
+    Mp4,
+}
+
+/// Number of bytes looked at to recognise a format, a lot more than the
+/// built-in formats need so that other decoders can look further.
+const SNIFF_SIZE: usize = 64;
+
+/// Size of an ID3v2 header, in which the tag size is a 28 bits "syncsafe"
+/// integer.
+const ID3V2_HEADER_SIZE: u64 = 10;
+
+/// Whether `header` starts with an MPEG audio frame header that makes sense:
+/// the sync word, then a layer, a bitrate and a sample rate that exist. ADTS
+/// (AAC) has the same sync word but a layer of 0, and is not MP3.
+fn is_mpeg_audio_frame(header: &[u8]) -> bool {
+    if header.len() < 4 || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return false;
+    }
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate = header[2] >> 4;
+    let sample_rate = (header[2] >> 2) & 0x03;
+    version != 1 && layer != 0 && bitrate != 0x0F && sample_rate != 0x03
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 6] = [
+        AudioFormat::Wav,
+        AudioFormat::Flac,
+        AudioFormat::Ogg,
+        AudioFormat::Mp3,
+        AudioFormat::Aiff,
+        AudioFormat::Mp4,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::Ogg => "Ogg",
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::Aiff => "AIFF",
+            AudioFormat::Mp4 => "MP4",
+        }
+    }
+
+    /// Lowercase extensions of the files of this format.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            AudioFormat::Wav => &["wav"],
+            AudioFormat::Flac => &["flac"],
+            AudioFormat::Ogg => &["ogg", "oga", "opus"],
+            AudioFormat::Mp3 => &["mp3"],
+            AudioFormat::Aiff => &["aif", "aiff", "aifc"],
+            AudioFormat::Mp4 => &["m4a", "mp4"],
+        }
+    }
+
+    /// Whether `header`, the first bytes of a file, are those of this format.
+    pub fn matches(self, header: &[u8]) -> bool {
+        match self {
+            AudioFormat::Wav => {
+                header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+            }
+            AudioFormat::Flac => header.starts_with(b"fLaC"),
+            AudioFormat::Ogg => header.starts_with(b"OggS"),
+            AudioFormat::Mp3 => is_mpeg_audio_frame(header),
+            AudioFormat::Aiff => {
+                header.len() >= 12
+                    && &header[0..4] == b"FORM"
+                    && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC")
+            }
+            AudioFormat::Mp4 => header.len() >= 8 && &header[4..8] == b"ftyp",
+        }
+    }
+}
+
+/// Reads as much of `buffer` as the file has, returning how much was read.
+fn read_up_to(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// The first bytes of a file, to recognise its format from.
+pub struct FileHeader {
+    /// Bytes following the ID3v2 tag if there is one, from the start otherwise.
+    pub bytes: Vec<u8>,
+    /// Whether the file starts with an ID3v2 tag, which is mostly found in MP3s.
+    pub id3v2: bool,
+}
+
+/// Size of the ID3v2 tag starting `data`, header and footer included, if it
+/// starts with one.
+pub fn id3v2_size(data: &[u8]) -> Option<u64> {
+    if data.len() < ID3V2_HEADER_SIZE as usize || !data.starts_with(b"ID3") {
+        return None;
+    }
+    let tag_size = data[6..10]
+        .iter()
+        .fold(0_u64, |size, byte| (size << 7) | (byte & 0x7F) as u64);
+    // a footer, as large as the header, may follow the tag
+    let footer_size = if data[5] & 0x10 != 0 {
+        ID3V2_HEADER_SIZE
+    } else {
+        0
+    };
+    Some(ID3V2_HEADER_SIZE + tag_size + footer_size)
+}
+
+/// Reads the first bytes of the file at `path`, skipping any ID3v2 tag so that
+/// what follows can be recognised.
+pub fn read_header(path: &str) -> Result<FileHeader, AnalysisError> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let mut header = vec![0; SNIFF_SIZE];
+    let read = read_up_to(&mut file, &mut header)?;
+    header.truncate(read);
+    let tag_size = match id3v2_size(&header) {
+        Some(size) => size,
+        None => {
+            return Ok(FileHeader {
+                bytes: header,
+                id3v2: false,
+            })
+        }
+    };
+    file.seek(SeekFrom::Start(tag_size))?;
+    header.resize(SNIFF_SIZE, 0);
+    let read = read_up_to(&mut file, &mut header)?;
+    header.truncate(read);
+    Ok(FileHeader {
+        bytes: header,
+        id3v2: true,
+    })
+}
+
+/// Lowercase extension of `path`, if it has one.
+pub fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+}
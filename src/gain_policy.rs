@@ -0,0 +1,152 @@
+use serde::Serialize;
+use std::str::FromStr;
+
+/// What gives when the gain reaching the target would take the true peak of a
+/// track over the ceiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GainPriority {
+    /// The gain is lowered for the peak to stay under the ceiling.
+    #[default]
+    Ceiling,
+    /// The gain reaches the target, the track only being marked.
+    Target,
+}
+
+impl FromStr for GainPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ceiling" => Ok(GainPriority::Ceiling),
+            "target" => Ok(GainPriority::Target),
+            _ => Err(format!("unknown gain priority: {}", s)),
+        }
+    }
+}
+
+/// Why the gain of a track isn't the one reaching the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Clamp {
+    /// Lowered to the maximum boost.
+    MaxBoost,
+    /// Raised to the maximum cut.
+    MaxCut,
+    /// Lowered for the true peak to stay under the ceiling.
+    Ceiling,
+}
+
+/// Limits to the gain written to tracks. Without any, it is always the one
+/// reaching the target.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GainPolicy {
+    /// Level in dBTP the true peak of tracks shouldn't go over once the gain
+    /// is applied.
+    pub ceiling: Option<f32>,
+    /// Largest gain in dB.
+    pub max_boost: Option<f32>,
+    /// Largest attenuation in dB, as a positive number.
+    pub max_cut: Option<f32>,
+    pub priority: GainPriority,
+}
+
+impl GainPolicy {
+    /// The gain to write to a track whose true peak is `peak` dBTP, instead of
+    /// `gain`, why when it isn't `gain`, and whether the track is over the
+    /// ceiling with it. The gain stays between the maximum boost and cut: when
+    /// staying under the ceiling would take more of a cut, the track is only
+    /// marked.
+    pub fn apply(&self, gain: f32, peak: f32) -> (f32, Option<Clamp>, bool) {
+        let (mut gain, mut clamp) = match (self.max_boost, self.max_cut) {
+            (Some(max_boost), _) if gain > max_boost => (max_boost, Some(Clamp::MaxBoost)),
+            (_, Some(max_cut)) if gain < -max_cut => (-max_cut, Some(Clamp::MaxCut)),
+            _ => (gain, None),
+        };
+        if let Some(ceiling) = self.ceiling.filter(|ceiling| peak + gain > *ceiling) {
+            match self.priority {
+                GainPriority::Ceiling => match self.max_cut {
+                    Some(max_cut) if ceiling - peak < -max_cut => {
+                        gain = -max_cut;
+                        clamp = Some(Clamp::MaxCut);
+                    }
+                    _ => {
+                        gain = ceiling - peak;
+                        clamp = Some(Clamp::Ceiling);
+                    }
+                },
+                GainPriority::Target => {}
+            }
+        }
+        let over_ceiling = self.ceiling.is_some_and(|ceiling| peak + gain > ceiling);
+        (gain, clamp, over_ceiling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_limits_the_gain_is_kept() {
+        assert_eq!(GainPolicy::default().apply(12.0, 3.0), (12.0, None, false));
+    }
+
+    #[test]
+    fn boost_and_cut_are_clamped() {
+        let policy = GainPolicy {
+            max_boost: Some(6.0),
+            max_cut: Some(10.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.apply(8.0, -20.0),
+            (6.0, Some(Clamp::MaxBoost), false)
+        );
+        assert_eq!(
+            policy.apply(-12.0, 0.0),
+            (-10.0, Some(Clamp::MaxCut), false)
+        );
+        assert_eq!(policy.apply(-4.0, 0.0), (-4.0, None, false));
+    }
+
+    #[test]
+    fn the_ceiling_limits_the_boost() {
+        let policy = GainPolicy {
+            ceiling: Some(-1.0),
+            max_boost: Some(6.0),
+            ..Default::default()
+        };
+        assert_eq!(policy.apply(8.0, -6.0), (5.0, Some(Clamp::Ceiling), false));
+        assert_eq!(policy.apply(4.0, -6.0), (4.0, None, false));
+
+        let policy = GainPolicy {
+            priority: GainPriority::Target,
+            ..policy
+        };
+        assert_eq!(policy.apply(4.0, -3.0), (4.0, None, true));
+    }
+
+    #[test]
+    fn boosts_limited_over_the_ceiling_are_marked_for_both() {
+        let policy = GainPolicy {
+            ceiling: Some(-1.0),
+            max_boost: Some(6.0),
+            priority: GainPriority::Target,
+            ..Default::default()
+        };
+        assert_eq!(policy.apply(8.0, -3.0), (6.0, Some(Clamp::MaxBoost), true));
+        assert_eq!(policy.apply(8.0, -8.0), (6.0, Some(Clamp::MaxBoost), false));
+    }
+
+    #[test]
+    fn the_ceiling_doesnt_go_past_the_maximum_cut() {
+        let policy = GainPolicy {
+            ceiling: Some(-1.0),
+            max_cut: Some(6.0),
+            ..Default::default()
+        };
+        assert_eq!(policy.apply(-2.0, 2.0), (-3.0, Some(Clamp::Ceiling), false));
+        assert_eq!(policy.apply(-2.0, 8.0), (-6.0, Some(Clamp::MaxCut), true));
+        assert_eq!(policy.apply(-8.0, 8.0), (-6.0, Some(Clamp::MaxCut), true));
+    }
+}
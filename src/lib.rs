@@ -1,16 +1,29 @@
 pub mod analysis;
+mod bands;
 mod cache;
+mod channels;
 mod collection;
+mod command;
+mod decoder;
 mod error;
+mod format;
+mod gain_policy;
 mod logging;
 mod models;
+mod mp3;
+mod mp4;
+mod overs;
 mod progress;
+mod replaygain;
 mod scanner;
+mod stereo;
+mod timeline;
 mod utils;
+mod weighting;
 
 use crate::logging::Logger;
 use crate::utils::exit_with_error;
-use clap::{command, Arg, Command};
+use clap::{command, Arg, ArgAction, Command};
 use log::LevelFilter::Warn;
 
 pub fn cli() {
@@ -86,6 +99,138 @@ pub fn cli() {
                 .long("difference-report")
                 .global(true)
             )
+            .arg(
+                Arg::new("stems")
+                .help("Also measure each stem of Traktor STEM files, for the difference report.")
+                .long("stems")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("decoder-command")
+                .help("Command decoding the files no built-in decoder handles, writing raw 32 bits float samples to stdout, e.g. \"ffmpeg -v error -i {input} -f f32le -ac {channels=2} -ar {rate=48000} -\".")
+                .long("decoder-command")
+            )
+            .arg(
+                Arg::new("tolerant")
+                .help("Skip the damaged frames of files instead of failing, leaving the gain of tracks of which too little could be measured untouched.")
+                .long("tolerant")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("min-coverage")
+                .help("With --tolerant, percentage of a track's duration that must be measured for its gain to be written.")
+                .long("min-coverage")
+                .default_value("95")
+            )
+            .arg(
+                Arg::new("channel-layout")
+                .help("Speakers fed by the channels of the files that have that many channels but don't tell which, such as AIFF files, e.g. \"FL,FR,FC,LFE,BL,BR\".")
+                .long("channel-layout")
+            )
+            .arg(
+                Arg::new("gain-basis")
+                .help("What the gain is derived from: the integrated loudness of the whole track, or that of its loudest section, such as the main drop.")
+                .long("gain-basis")
+                .value_parser(["integrated", "loudest-section"])
+                .default_value("integrated")
+            )
+            .arg(
+                Arg::new("section-length")
+                .help("With --gain-basis loudest-section, length of the sections in seconds.")
+                .long("section-length")
+                .default_value("10")
+            )
+            .arg(
+                Arg::new("section-percentile")
+                .help("With --gain-basis loudest-section, percentile of the loudness of the sections to use, 100 being the loudest one.")
+                .long("section-percentile")
+                .default_value("100")
+            )
+            .arg(
+                Arg::new("cue-region")
+                .help("Only measure tracks between two types of cues, e.g. \"load:fade-out\", or within their first saved loop with \"loop\". Types are cue, fade-in, fade-out, load, grid and loop.")
+                .long("cue-region")
+            )
+            .arg(
+                Arg::new("algorithm")
+                .help("Algorithm measuring the loudness the gain is derived from: bs1770, replaygain1 (44100 and 48000Hz only), rms, or peak, with which the target is that of the peaks.")
+                .long("algorithm")
+                .value_parser(["bs1770", "replaygain1", "rms", "peak"])
+                .default_value("bs1770")
+            )
+            .arg(
+                Arg::new("weighting")
+                .help("With --algorithm bs1770, filters weighting the frequencies of the audio: k, the standard K-weighting, or flat-low-end, counting sub-bass as much as the mids.")
+                .long("weighting")
+                .value_parser(["k", "flat-low-end"])
+                .default_value("k")
+            )
+            .arg(
+                Arg::new("weighting-file")
+                .help("With --algorithm bs1770, weight the audio with the biquads of a JSON file instead, for each sample rate, e.g. {\"48000\": [{\"b\": [b0, b1, b2], \"a\": [a1, a2]}]}.")
+                .long("weighting-file")
+            )
+            .arg(
+                Arg::new("ceiling")
+                .help("Level in dBTP the true peak of tracks shouldn't go over once their gain is applied, e.g. -1.")
+                .long("ceiling")
+                .allow_hyphen_values(true)
+            )
+            .arg(
+                Arg::new("max-boost")
+                .help("Largest gain in dB written to tracks.")
+                .long("max-boost")
+            )
+            .arg(
+                Arg::new("max-cut")
+                .help("Largest attenuation in dB written to tracks, as a positive number.")
+                .long("max-cut")
+            )
+            .arg(
+                Arg::new("gain-priority")
+                .help("With --ceiling, whether tracks whose true peak would go over it get less gain (ceiling), or keep the one reaching the target and are only marked in the difference report (target).")
+                .long("gain-priority")
+                .requires("ceiling")
+                .value_parser(["ceiling", "target"])
+                .default_value("ceiling")
+            )
+            .arg(
+                Arg::new("count-overs")
+                .help("Count the samples of tracks over 0 dBTP, and over --ceiling, once their gain is applied, as Traktor's limiter would see them.")
+                .long("count-overs")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("stereo")
+                .help("Analyse how stereo files hold up in mono: the correlation of their channels, overall and below 150Hz, and the loudness of their mono sum.")
+                .long("stereo")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("max-mono-loss")
+                .help("With --stereo, loss of loudness in LU in mono beyond which tracks are flagged.")
+                .long("max-mono-loss")
+                .default_value("3")
+            )
+            .arg(
+                Arg::new("band-energy")
+                .help("Measure how the energy of files is spread across the spectrum: the level of the sub (below 60Hz), bass (to 250Hz), low-mid (to 500Hz), mid (to 4kHz) and high bands, relative to the whole.")
+                .long("band-energy")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("silence-threshold")
+                .help("Find the silence at the edges of files, where the level stays below this, in dBFS, e.g. -60.")
+                .long("silence-threshold")
+                .allow_hyphen_values(true)
+            )
+            .arg(
+                Arg::new("exclude-silence")
+                .help("Leave the silence at the edges of files out of the measurement.")
+                .long("exclude-silence")
+                .requires("silence-threshold")
+                .action(ArgAction::SetTrue)
+            )
         )
         .subcommand(
             command!("scanner")
@@ -96,6 +241,95 @@ pub fn cli() {
                 .required(true)
                 .index(1)
             )
+            .arg(
+                Arg::new("stems")
+                .help("Also measure each stem of Traktor STEM files.")
+                .long("stems")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("decoder-command")
+                .help("Command decoding the files no built-in decoder handles, writing raw 32 bits float samples to stdout, e.g. \"ffmpeg -v error -i {input} -f f32le -ac {channels=2} -ar {rate=48000} -\".")
+                .long("decoder-command")
+            )
+            .arg(
+                Arg::new("tolerant")
+                .help("Skip the damaged frames of files instead of failing, and show how long what was measured is.")
+                .long("tolerant")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("channel-layout")
+                .help("Speakers fed by the channels of the files that have that many channels but don't tell which, such as AIFF files, e.g. \"FL,FR,FC,LFE,BL,BR\".")
+                .long("channel-layout")
+            )
+            .arg(
+                Arg::new("timeline")
+                .help("Write the momentary and short-term loudness of each file over time, as csv or json.")
+                .long("timeline")
+            )
+            .arg(
+                Arg::new("timeline-hop")
+                .help("Interval in milliseconds at which the timeline records the loudness.")
+                .long("timeline-hop")
+                .default_value("100")
+            )
+            .arg(
+                Arg::new("timeline-dir")
+                .help("Directory the timelines are written to, each named after its file.")
+                .long("timeline-dir")
+                .default_value(".")
+            )
+            .arg(
+                Arg::new("algorithm")
+                .help("Algorithm measuring the loudness the gain is derived from: bs1770, replaygain1 (44100 and 48000Hz only), rms, or peak, with which the target is that of the peaks.")
+                .long("algorithm")
+                .value_parser(["bs1770", "replaygain1", "rms", "peak"])
+                .default_value("bs1770")
+            )
+            .arg(
+                Arg::new("weighting")
+                .help("With --algorithm bs1770, filters weighting the frequencies of the audio: k, the standard K-weighting, or flat-low-end, counting sub-bass as much as the mids.")
+                .long("weighting")
+                .value_parser(["k", "flat-low-end"])
+                .default_value("k")
+            )
+            .arg(
+                Arg::new("weighting-file")
+                .help("With --algorithm bs1770, weight the audio with the biquads of a JSON file instead, for each sample rate, e.g. {\"48000\": [{\"b\": [b0, b1, b2], \"a\": [a1, a2]}]}.")
+                .long("weighting-file")
+            )
+            .arg(
+                Arg::new("stereo")
+                .help("Analyse how stereo files hold up in mono: the correlation of their channels, overall and below 150Hz, and the loudness of their mono sum.")
+                .long("stereo")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("max-mono-loss")
+                .help("With --stereo, loss of loudness in LU in mono beyond which tracks are flagged.")
+                .long("max-mono-loss")
+                .default_value("3")
+            )
+            .arg(
+                Arg::new("band-energy")
+                .help("Measure how the energy of files is spread across the spectrum: the level of the sub (below 60Hz), bass (to 250Hz), low-mid (to 500Hz), mid (to 4kHz) and high bands, relative to the whole.")
+                .long("band-energy")
+                .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("silence-threshold")
+                .help("Find the silence at the edges of files, where the level stays below this, in dBFS, e.g. -60.")
+                .long("silence-threshold")
+                .allow_hyphen_values(true)
+            )
+            .arg(
+                Arg::new("exclude-silence")
+                .help("Leave the silence at the edges of files out of the measurement.")
+                .long("exclude-silence")
+                .requires("silence-threshold")
+                .action(ArgAction::SetTrue)
+            )
         );
 
     match command.get_matches().subcommand() {
@@ -1,5 +1,9 @@
+use crate::analysis::{
+    AnalysisError, BandEnergy, Clamp, Dynamics, Overs, Silence, StemLoudness, Stereo,
+};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
@@ -110,6 +114,61 @@ pub struct CueV2 {
     pub start: String,
 }
 
+/// What a cue marks, as Traktor numbers them in `CueV2::cue_type`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CueType {
+    Cue,
+    FadeIn,
+    FadeOut,
+    Load,
+    Grid,
+    Loop,
+}
+
+impl CueType {
+    const ALL: [(CueType, i64, &'static str); 6] = [
+        (CueType::Cue, 0, "cue"),
+        (CueType::FadeIn, 1, "fade-in"),
+        (CueType::FadeOut, 2, "fade-out"),
+        (CueType::Load, 3, "load"),
+        (CueType::Grid, 4, "grid"),
+        (CueType::Loop, 5, "loop"),
+    ];
+}
+
+/// Parses the names of the types, e.g. `fade-out`.
+impl FromStr for CueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CueType::ALL
+            .iter()
+            .find(|(_, _, name)| name.eq_ignore_ascii_case(s))
+            .map(|(cue_type, _, _)| *cue_type)
+            .ok_or_else(|| format!("unknown cue type: {}", s))
+    }
+}
+
+impl CueV2 {
+    /// What the cue marks, unless Traktor added a type since.
+    pub fn kind(&self) -> Option<CueType> {
+        CueType::ALL
+            .iter()
+            .find(|(_, number, _)| *number == self.cue_type)
+            .map(|(cue_type, _, _)| *cue_type)
+    }
+
+    /// Milliseconds from the start of the track, if Traktor wrote a number.
+    pub fn start_ms(&self) -> Option<f64> {
+        self.start.parse().ok()
+    }
+
+    /// Milliseconds the cue lasts, which only loops do.
+    pub fn length_ms(&self) -> Option<f64> {
+        self.length.parse().ok()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Location {
     #[serde(rename = "DIR")]
@@ -259,4 +318,54 @@ pub struct AnalysisDifference {
     pub computed_analyzed_db: f64,
     pub computed_perceived_db: f64,
     pub computed_peak_db: f64,
+    /// Loudness range in LU, unknown for results cached before it was
+    /// measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computed_loudness_range: Option<f64>,
+    /// Loudness of the loudest section, when the gain is based on it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computed_section_loudness: Option<f64>,
+    /// Peak to loudness ratios, unknown for results cached before they were
+    /// measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamics: Option<Dynamics>,
+    /// How the track holds up in mono, when asked for and it is stereo.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stereo: Option<Stereo>,
+    /// Whether the track loses more than the maximum loss in mono.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub loses_level_in_mono: bool,
+    /// Level of each band relative to the whole track in dB, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub band_energy: Option<BandEnergy>,
+    /// Name of the algorithm the gain was derived with.
+    pub algorithm: &'static str,
+    /// Silence at the edges of the track, when asked to find it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silence: Option<Silence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stems: Option<Vec<StemLoudness>>,
+    /// Percentage of the track's duration that was measured, in tolerant mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<f64>,
+    /// Why the gain isn't the one reaching the target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clamp: Option<Clamp>,
+    /// Whether the true peak of the track is over the ceiling with the gain.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub over_ceiling: bool,
+    /// Gain in dB that would have reached the target, when clamped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_gain_db: Option<f64>,
+    /// Samples over 0dBTP, and over the ceiling, once the gain is applied,
+    /// when asked to count them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overs: Option<Vec<Overs>>,
+}
+
+/// A track of the collection that couldn't be analysed, and why.
+#[derive(Debug)]
+pub struct AnalysisFailure {
+    pub path: String,
+    pub error: AnalysisError,
 }
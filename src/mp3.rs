@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::format::id3v2_size;
+
+/// Samples of delay that decoders add to those of the encoder, as LAME counts
+/// them.
+pub const DECODER_DELAY: u64 = 528 + 1;
+
+/// Size of an ID3v1 tag, at the very end of a file.
+const ID3V1_SIZE: u64 = 128;
+
+/// Size of the header and of the footer of an APEv2 tag.
+const APE_HEADER_SIZE: u64 = 32;
+
+/// What the Xing (or Info, for CBR files) frame that LAME writes before the
+/// audio tells: the number of audio frames, and the encoder delay and padding
+/// from the LAME tag that follows it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct XingHeader {
+    pub frames: Option<u32>,
+    /// Encoder delay and padding, in samples per channel.
+    pub gapless: Option<(u32, u32)>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Parses the Xing header of `frame`, the bytes of the first MPEG audio frame
+/// of a file, if it has one. It comes after the side information, whose size
+/// depends on the MPEG version and the number of channels.
+pub fn parse_xing(frame: &[u8]) -> Option<XingHeader> {
+    let header = frame.get(0..4)?;
+    let mpeg1 = header[1] & 0x08 != 0;
+    let mono = header[3] >> 6 == 3;
+    let crc = if header[1] & 0x01 == 0 { 2 } else { 0 };
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let mut offset = 4 + crc + side_info;
+    let tag = frame.get(offset..offset + 4)?;
+    if tag != b"Xing" && tag != b"Info" {
+        return None;
+    }
+    let flags = read_u32(frame, offset + 4)?;
+    offset += 8;
+
+    let mut xing = XingHeader::default();
+    if flags & 0x01 != 0 {
+        xing.frames = Some(read_u32(frame, offset)?);
+        offset += 4;
+    }
+    // size in bytes, table of contents, quality
+    for (flag, size) in [(0x02, 4), (0x04, 100), (0x08, 4)] {
+        if flags & flag != 0 {
+            offset += size;
+        }
+    }
+
+    // The LAME tag: encoder version, then delay and padding as two 12 bits
+    // numbers at byte 21.
+    if let Some(lame) = frame.get(offset..offset + 24) {
+        if lame.starts_with(b"LAME") || lame.starts_with(b"Lavc") || lame.starts_with(b"Lavf") {
+            let delay = ((lame[21] as u32) << 4) | (lame[22] as u32 >> 4);
+            let padding = (((lame[22] & 0x0F) as u32) << 8) | lame[23] as u32;
+            xing.gapless = Some((delay, padding));
+        }
+    }
+    Some(xing)
+}
+
+/// Finds where the MPEG audio frames of the file are, leaving out the ID3v2
+/// tags at the start, and the APEv2 and ID3v1 tags at the end, whose content
+/// could be taken for frames.
+pub fn audio_range(file: &mut File) -> std::io::Result<(u64, u64)> {
+    let length = file.metadata()?.len();
+
+    let mut start = 0;
+    let mut header = [0; 10];
+    loop {
+        file.seek(SeekFrom::Start(start))?;
+        if file.read_exact(&mut header).is_err() {
+            break;
+        }
+        match id3v2_size(&header) {
+            Some(size) => start += size,
+            None => break,
+        }
+    }
+
+    let mut end = length;
+    if end >= start + ID3V1_SIZE {
+        let mut tag = [0; 3];
+        file.seek(SeekFrom::Start(end - ID3V1_SIZE))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= ID3V1_SIZE;
+        }
+    }
+    if end >= start + APE_HEADER_SIZE {
+        let mut footer = [0; APE_HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(end - APE_HEADER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        if footer.starts_with(b"APETAGEX") {
+            // the size counts the items and the footer, but not the header
+            let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header_size = if flags & 0x8000_0000 != 0 {
+                APE_HEADER_SIZE
+            } else {
+                0
+            };
+            end = end.saturating_sub(size + header_size).max(start);
+        }
+    }
+    file.seek(SeekFrom::Start(start))?;
+    Ok((start, end))
+}
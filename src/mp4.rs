@@ -0,0 +1,264 @@
+use crate::error::AnalysisError;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+
+/// Where the audible part of a track is, in the track's media time scale: the
+/// encoder priming to skip, and the duration to play once skipped.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Gapless {
+    pub priming: u64,
+    pub duration: Option<u64>,
+}
+
+/// What the audio decoders don't tell about a track of an MP4 file.
+#[derive(Debug)]
+pub struct Mp4Track {
+    /// Handler type of the track: `soun` for audio.
+    pub handler: [u8; 4],
+    /// Time scale of the track's media, in which `gapless` is expressed.
+    pub timescale: u32,
+    pub gapless: Option<Gapless>,
+}
+
+/// The tracks of an MP4 file, and the names of the stems for Traktor STEM files.
+#[derive(Debug)]
+pub struct Mp4Info {
+    pub tracks: Vec<Mp4Track>,
+    pub stem_names: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StemMetadata {
+    stems: Vec<StemDescription>,
+}
+
+#[derive(Deserialize)]
+struct StemDescription {
+    name: String,
+}
+
+impl Mp4Track {
+    pub fn is_audio(&self) -> bool {
+        &self.handler == b"soun"
+    }
+}
+
+/// An edit list entry, in movie time scale for the duration and media time
+/// scale for the start.
+struct Edit {
+    segment_duration: u64,
+    media_time: i64,
+}
+
+/// Iterates over the boxes contained in `data`, yielding their type and content.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+        let mut size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as u64;
+        let mut header = 8;
+        if size == 1 {
+            size = u64::from_be_bytes(data.get(8..16)?.try_into().unwrap());
+            header = 16;
+        } else if size == 0 {
+            size = data.len() as u64;
+        }
+        if size < header as u64 || size > data.len() as u64 {
+            return None;
+        }
+        let (current, rest) = data.split_at(size as usize);
+        data = rest;
+        Some((&current[4..8], &current[header..]))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], box_type: &[u8]) -> Option<&'a [u8]> {
+    boxes(data).find(|(t, _)| *t == box_type).map(|(_, b)| b)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns the time scale of a `mvhd` or `mdhd` box, whose layouts are the
+/// same up to that field.
+fn header_timescale(data: &[u8]) -> Option<u32> {
+    match data.first()? {
+        1 => read_u32(data, 20),
+        _ => read_u32(data, 12),
+    }
+}
+
+fn parse_elst(data: &[u8]) -> Option<Vec<Edit>> {
+    let version = *data.first()?;
+    let count = read_u32(data, 4)? as usize;
+    let mut edits = Vec::new();
+    for i in 0..count {
+        edits.push(if version == 1 {
+            let offset = 8 + i * 20;
+            Edit {
+                segment_duration: read_u64(data, offset)?,
+                media_time: read_u64(data, offset + 8)? as i64,
+            }
+        } else {
+            let offset = 8 + i * 12;
+            Edit {
+                segment_duration: read_u32(data, offset)? as u64,
+                media_time: read_u32(data, offset + 4)? as i32 as i64,
+            }
+        });
+    }
+    Some(edits)
+}
+
+/// Turns an edit list into the part of the media that is played. Empty edits
+/// (`media_time` of -1) only delay the start of the presentation and are
+/// skipped.
+fn gapless_from_edits(edits: &[Edit], movie_timescale: u32, timescale: u32) -> Option<Gapless> {
+    let edit = edits.iter().find(|e| e.media_time >= 0)?;
+    let duration = if edit.segment_duration == 0 || movie_timescale == 0 {
+        None
+    } else {
+        Some(edit.segment_duration * timescale as u64 / movie_timescale as u64)
+    };
+    Some(Gapless {
+        priming: edit.media_time as u64,
+        duration,
+    })
+}
+
+/// Parses iTunes' gapless metadata, ` 00000000 <priming> <padding> <length> ...`,
+/// all in hexadecimal and in frames.
+fn parse_itunsmpb(value: &str) -> Option<Gapless> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let priming = u64::from_str_radix(fields.get(1)?, 16).ok()?;
+    let duration = u64::from_str_radix(fields.get(3)?, 16).ok()?;
+    Some(Gapless {
+        priming,
+        duration: if duration == 0 { None } else { Some(duration) },
+    })
+}
+
+/// Looks up a freeform (`----`) iTunes metadata item in `moov`.
+fn itunes_freeform<'a>(moov: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let meta = find_box(find_box(moov, b"udta")?, b"meta")?;
+    // `meta` is a full box, the version and flags come first
+    let ilst = find_box(meta.get(4..)?, b"ilst")?;
+    boxes(ilst)
+        .filter(|(t, _)| *t == b"----")
+        .find(|(_, item)| find_box(item, b"name").and_then(|n| n.get(4..)) == Some(name.as_bytes()))
+        .and_then(|(_, item)| find_box(item, b"data"))
+        .and_then(|data| data.get(8..))
+}
+
+/// Reads the `moov` box of the MP4 file at `path`, skipping over the media data.
+pub fn read_moov(path: &str) -> Result<Vec<u8>, AnalysisError> {
+    let invalid = || AnalysisError::Decode(format!("invalid mp4: {}", &path));
+    let mut reader = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return Err(AnalysisError::Io(format!("file not found: {}", &path))),
+    };
+    let length = reader
+        .get_ref()
+        .metadata()
+        .map_err(|e| AnalysisError::Io(format!("{}: {}", e, &path)))?
+        .len();
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).map_err(|_| invalid())?;
+        let mut size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            let mut large_size = [0; 8];
+            reader.read_exact(&mut large_size).map_err(|_| invalid())?;
+            size = u64::from_be_bytes(large_size);
+            header_size = 16;
+        }
+        if size < header_size {
+            // a size of 0 means "until the end of the file": no moov after that
+            return Err(invalid());
+        }
+        if &header[4..8] == b"moov" {
+            // the size comes from the file: a damaged one mustn't have more
+            // allocated than the file holds
+            let position = reader.stream_position().map_err(|_| invalid())?;
+            if size - header_size > length.saturating_sub(position) {
+                return Err(invalid());
+            }
+            let mut moov = vec![0; (size - header_size) as usize];
+            reader.read_exact(&mut moov).map_err(|_| invalid())?;
+            return Ok(moov);
+        }
+        reader
+            .seek(SeekFrom::Current((size - header_size) as i64))
+            .map_err(|_| invalid())?;
+    }
+}
+
+/// Reads the names of the stems from the JSON in the `stem` box that Native
+/// Instruments' stem creator writes in `moov/udta`.
+fn stem_names(moov: &[u8]) -> Vec<String> {
+    find_box(moov, b"udta")
+        .and_then(|udta| find_box(udta, b"stem"))
+        .and_then(|json| serde_json::from_slice::<StemMetadata>(json).ok())
+        .map(|metadata| metadata.stems.into_iter().map(|s| s.name).collect())
+        .unwrap_or_default()
+}
+
+/// Lists the tracks of the MP4 file at `path`, in the order of the file, which
+/// is also the order in which the demuxer numbers them.
+pub fn read(path: &str) -> Result<Mp4Info, AnalysisError> {
+    let moov = read_moov(path)?;
+    let movie_timescale = find_box(&moov, b"mvhd")
+        .and_then(header_timescale)
+        .unwrap_or(0);
+
+    let mut tracks = Vec::new();
+    for (_, trak) in boxes(&moov).filter(|(t, _)| *t == b"trak") {
+        let mdia = match find_box(trak, b"mdia") {
+            Some(mdia) => mdia,
+            None => continue,
+        };
+        let timescale = find_box(mdia, b"mdhd")
+            .and_then(header_timescale)
+            .unwrap_or(0);
+        let mut handler = [0; 4];
+        if let Some(h) = find_box(mdia, b"hdlr").and_then(|h| h.get(8..12)) {
+            handler.copy_from_slice(h);
+        }
+        let gapless = find_box(trak, b"edts")
+            .and_then(|edts| find_box(edts, b"elst"))
+            .and_then(parse_elst)
+            .and_then(|edits| gapless_from_edits(&edits, movie_timescale, timescale));
+        tracks.push(Mp4Track {
+            handler,
+            timescale,
+            gapless,
+        });
+    }
+
+    // Without an edit list, iTunes' own metadata describes the first audio track.
+    if let Some(first_audio) = tracks.iter_mut().find(|t| t.is_audio()) {
+        if first_audio.gapless.is_none() {
+            first_audio.gapless = itunes_freeform(&moov, "iTunSMPB")
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .and_then(parse_itunsmpb);
+        }
+    }
+
+    Ok(Mp4Info {
+        tracks,
+        stem_names: stem_names(&moov),
+    })
+}
@@ -0,0 +1,265 @@
+use serde::Serialize;
+use std::f64::consts::PI;
+
+use crate::utils::db_to_linear;
+
+/// Taps of the interpolation filter, over all its phases: those of the
+/// ebur128 crate, which leaves out the last of the 49 of libebur128, always
+/// zero, for them to be a multiple of the oversampling factors.
+const TAPS: usize = 48;
+
+/// Samples over a level once the gain of a track is applied, its true peak
+/// being measured as BS.1770 does.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Overs {
+    /// Level in dBTP above which samples are over.
+    pub threshold: f32,
+    /// Number of samples per channel at which any channel is over.
+    pub samples: u64,
+    /// How long these samples last, in seconds.
+    pub duration: f64,
+    /// Number of runs of consecutive samples over: how many times the
+    /// limiter kicks in.
+    pub runs: u64,
+}
+
+/// Oversamples a channel with a Hann-windowed sinc, with the coefficients the
+/// ebur128 crate measures the true peak with.
+struct Interpolator {
+    /// Coefficients of each phase, for each of the last inputs.
+    filter: Vec<Vec<f32>>,
+    /// Last inputs, the latest first.
+    inputs: Vec<f32>,
+}
+
+impl Interpolator {
+    fn new(factor: usize) -> Self {
+        let taps = TAPS / factor;
+        let coefficient = |j: usize| {
+            // the window spans the 49 taps of libebur128
+            let window = 0.5 * (1.0 - (2.0 * PI * j as f64 / TAPS as f64).cos());
+            let m = j as f64 - (TAPS / 2) as f64;
+            let x = m * PI / factor as f64;
+            if m.abs() > 0.000001 {
+                (window * x.sin() / x) as f32
+            } else {
+                window as f32
+            }
+        };
+        Interpolator {
+            filter: (0..taps)
+                .map(|tap| {
+                    (0..factor)
+                        .map(|phase| coefficient(tap * factor + phase))
+                        .collect()
+                })
+                .collect(),
+            inputs: vec![0.0; taps],
+        }
+    }
+
+    /// The peak of the samples interpolated up to `input`.
+    fn peak(&mut self, input: f32) -> f32 {
+        self.inputs.rotate_right(1);
+        self.inputs[0] = input;
+        let mut peak = 0f32;
+        for phase in 0..self.filter[0].len() {
+            let sample: f32 = self
+                .filter
+                .iter()
+                .zip(&self.inputs)
+                .map(|(coefficients, input)| coefficients[phase] * input)
+                .sum();
+            peak = peak.max(sample.abs());
+        }
+        peak
+    }
+}
+
+/// Level in dBTP, before the gain, from which samples are counted: tracks
+/// are never boosted by 40dB.
+const LOWEST_LEVEL: f32 = -40.0;
+
+/// Step in dB between the levels samples are counted over.
+const LEVEL_STEP: f32 = 0.1;
+
+/// Number of levels, up to +12dBTP.
+const LEVELS: usize = 520;
+
+/// Samples of a track over each level, before the gain, for the overs of
+/// whatever gain it gets to be known once it is, without decoding the track
+/// again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakHistogram {
+    pub rate: u32,
+    /// Number of samples per channel over each level, from `LOWEST_LEVEL`
+    /// in steps of `LEVEL_STEP`, those past the last one being zero.
+    pub samples: Vec<u64>,
+    /// Number of runs of consecutive samples over each level.
+    pub runs: Vec<u64>,
+}
+
+impl PeakHistogram {
+    /// The samples over `threshold` in dBTP once `gain` in dB is applied, to
+    /// within half a step.
+    pub fn overs(&self, gain: f32, threshold: f32) -> Overs {
+        let level = ((threshold - gain - LOWEST_LEVEL) / LEVEL_STEP).round();
+        let at = |counts: &[u64]| counts.get(level.max(0.0) as usize).copied().unwrap_or(0);
+        let samples = at(&self.samples);
+        Overs {
+            threshold,
+            samples,
+            duration: samples as f64 / self.rate.max(1) as f64,
+            runs: at(&self.runs),
+        }
+    }
+}
+
+/// Counts the samples over each level, their true peak being measured as
+/// BS.1770 does.
+pub struct PeakCounter {
+    rate: u32,
+    interpolators: Vec<Interpolator>,
+    /// Levels in linear.
+    levels: Vec<f32>,
+    /// Number of samples over each level, and none of the next ones.
+    highest: Vec<u64>,
+    /// Runs starting at each level, less those starting at the previous one.
+    run_starts: Vec<i64>,
+    /// Number of levels the last sample was over.
+    over: usize,
+}
+
+impl PeakCounter {
+    pub fn new(channels: u32, rate: u32) -> Self {
+        // as the ebur128 crate, which doesn't oversample from 192kHz
+        let factor = match rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+        PeakCounter {
+            rate,
+            interpolators: (0..channels).map(|_| Interpolator::new(factor)).collect(),
+            levels: (0..LEVELS)
+                .map(|level| db_to_linear(LOWEST_LEVEL + level as f32 * LEVEL_STEP))
+                .collect(),
+            highest: vec![0; LEVELS],
+            run_starts: vec![0; LEVELS + 1],
+            over: 0,
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.interpolators.len()) {
+            let peak = frame
+                .iter()
+                .zip(self.interpolators.iter_mut())
+                .map(|(sample, interpolator)| interpolator.peak(*sample))
+                .fold(0f32, f32::max);
+            let over = self.levels.partition_point(|level| *level < peak);
+            if over > 0 {
+                self.highest[over - 1] += 1;
+            }
+            // a run starts at each level over which the last sample wasn't
+            if over > self.over {
+                self.run_starts[self.over] += 1;
+                self.run_starts[over] -= 1;
+            }
+            self.over = over;
+        }
+    }
+
+    pub fn finish(&self) -> PeakHistogram {
+        let mut samples: Vec<u64> = self
+            .highest
+            .iter()
+            .rev()
+            .scan(0, |over, highest| {
+                *over += highest;
+                Some(*over)
+            })
+            .collect();
+        samples.reverse();
+        let mut runs: Vec<u64> = self.run_starts[..LEVELS]
+            .iter()
+            .scan(0, |runs, starts| {
+                *runs += starts;
+                Some(*runs as u64)
+            })
+            .collect();
+        // the levels nothing is over are left out
+        let over = samples.iter().take_while(|samples| **samples > 0).count();
+        samples.truncate(over);
+        runs.truncate(over);
+        PeakHistogram {
+            rate: self.rate,
+            samples,
+            runs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sine at a quarter of the rate, its samples falling between its
+    /// peaks: its sample peak is 3dB below its true peak, which every other
+    /// sample reaches.
+    fn quarter_rate_sine(amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (i as f32 * std::f32::consts::FRAC_PI_2 + 0.785).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn level(amplitude: f32, frames: usize) -> Vec<f32> {
+        vec![amplitude; frames * 2]
+    }
+
+    fn histogram(samples: &[f32]) -> PeakHistogram {
+        let mut counter = PeakCounter::new(2, 48000);
+        counter.add(samples);
+        counter.finish()
+    }
+
+    #[test]
+    fn samples_over_each_threshold_are_counted() {
+        // -3dBFS once gained
+        let histogram = histogram(&level(0.5, 48000));
+        assert_eq!(histogram.overs(3.0, 0.0).samples, 0);
+        let overs = histogram.overs(3.0, -6.0);
+        assert!(overs.samples > 47900);
+        assert_eq!(overs.runs, 1);
+        assert!((overs.duration - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn inter_sample_overs_are_counted() {
+        let samples = quarter_rate_sine(0.5, 48000);
+        assert!(samples
+            .iter()
+            .all(|sample| sample * db_to_linear(7.0) < 1.0));
+        let overs = histogram(&samples).overs(7.0, 0.0);
+        assert!(overs.samples > 23900);
+    }
+
+    #[test]
+    fn overs_are_counted_in_runs() {
+        let mut samples = level(0.1, 4800);
+        samples.extend(level(0.8, 4800));
+        samples.extend(level(0.1, 4800));
+        samples.extend(level(0.8, 4800));
+        let histogram = histogram(&samples);
+        assert_eq!(histogram.overs(0.0, 0.0).samples, 0);
+        let overs = histogram.overs(0.0, -6.0);
+        assert_eq!(overs.runs, 2);
+        assert!((overs.duration - 0.2).abs() < 0.01);
+        // over -30dBTP, it all is
+        assert_eq!(histogram.overs(0.0, -30.0).runs, 1);
+    }
+}
@@ -0,0 +1,291 @@
+use std::f64::consts::PI;
+
+use crate::error::AnalysisError;
+use crate::weighting::{self, Biquad};
+
+/// Level in dB that pink noise at -20dBFS RMS has, in the scale of the
+/// histogram: it gets no gain.
+const PINK_REFERENCE: f64 = 64.82;
+
+/// Length of the blocks whose level is measured, in seconds.
+const BLOCK_TIME: f64 = 0.05;
+
+/// Steps of the histogram of the block levels, per dB.
+const STEPS_PER_DB: f64 = 100.0;
+
+/// Highest level of the histogram, in dB.
+const MAX_DB: f64 = 120.0;
+
+/// The level of the loudest blocks is taken at this proportion of them.
+const PERCENTILE: f64 = 0.95;
+
+/// Cutoff in Hz of the low-pass filter taking audio at a multiple of the
+/// rates of the filters down to them, under their Nyquist frequency.
+const DECIMATION_CUTOFF: f64 = 18000.0;
+
+/// Order of that low-pass filter, a Butterworth one.
+const DECIMATION_ORDER: usize = 8;
+
+/// Yule-Walker filter of the equal loudness curve, as `b0, a1, b1, a2, b2…`.
+const YULE_44100: [f64; 21] = [
+    0.05418656406430,
+    -3.47845948550071,
+    -0.02911007808948,
+    6.36317777566148,
+    -0.00848709379851,
+    -8.54751527471874,
+    -0.00851165645469,
+    9.47693607801280,
+    -0.00834990904936,
+    -8.81498681370155,
+    0.02245293253339,
+    6.85401540936998,
+    -0.02596338512915,
+    -4.39470996079559,
+    0.01624864962975,
+    2.19611684890774,
+    -0.00240879051584,
+    -0.75104302451432,
+    0.00674613682247,
+    0.13149317958808,
+    -0.00187763777362,
+];
+
+const YULE_48000: [f64; 21] = [
+    0.03857599435200,
+    -3.84664617118067,
+    -0.02160367184185,
+    7.81501653005538,
+    -0.00123395316851,
+    -11.34170355132042,
+    -0.00009291677959,
+    13.05504219327545,
+    -0.01655260341619,
+    -12.28759895145294,
+    0.02161526843274,
+    9.48293806319790,
+    -0.02074045215285,
+    -5.87257861775999,
+    0.00594298065125,
+    2.75465861874613,
+    0.00306428023191,
+    -0.86984376593551,
+    0.00012025322027,
+    0.13919314567432,
+    0.00288463683916,
+];
+
+/// Butterworth high-pass filter following it, in the same order.
+const BUTTER_44100: [f64; 5] = [
+    0.98500175787242,
+    -1.96977855582618,
+    -1.97000351574484,
+    0.97022847566350,
+    0.98500175787242,
+];
+
+const BUTTER_48000: [f64; 5] = [
+    0.98621192462708,
+    -1.97223372919527,
+    -1.97242384925416,
+    0.97261396931306,
+    0.98621192462708,
+];
+
+/// An IIR filter of the form of those of ReplayGain, with its state for a
+/// channel.
+struct Filter {
+    coefficients: &'static [f64],
+    /// Last inputs and outputs, the latest first.
+    inputs: Vec<f64>,
+    outputs: Vec<f64>,
+}
+
+impl Filter {
+    fn new(coefficients: &'static [f64]) -> Self {
+        let order = coefficients.len() / 2;
+        Filter {
+            coefficients,
+            inputs: vec![0.0; order],
+            outputs: vec![0.0; order],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let mut output = self.coefficients[0] * input;
+        for k in 0..self.inputs.len() {
+            output += self.coefficients[2 * k + 2] * self.inputs[k]
+                - self.coefficients[2 * k + 1] * self.outputs[k];
+        }
+        self.inputs.rotate_right(1);
+        self.inputs[0] = input;
+        self.outputs.rotate_right(1);
+        self.outputs[0] = output;
+        output
+    }
+}
+
+/// Measures audio as ReplayGain 1.0 does: the level of 50ms blocks of it,
+/// filtered by an equal loudness curve, at the 95th percentile. Audio at a
+/// multiple of 44100 or 48000Hz is measured once taken down to that rate.
+pub struct ReplayGain {
+    /// Equal loudness filters of each channel.
+    filters: Vec<(Filter, Filter)>,
+    /// Frames taken down to one, and the low-pass filters of each channel
+    /// before that.
+    decimation: usize,
+    low_pass: Vec<Vec<weighting::Filter>>,
+    /// Frames to go until the next one kept, and the last one filtered.
+    skipped: usize,
+    frame: Vec<f64>,
+    block_frames: usize,
+    /// Sum of the squares of the filtered samples of the current block, and
+    /// how many frames it has so far.
+    block_sum: f64,
+    block_count: usize,
+    /// Number of blocks at each level, in `1 / STEPS_PER_DB` dB steps.
+    histogram: Vec<u64>,
+}
+
+impl ReplayGain {
+    pub fn new(channels: u32, rate: u32) -> Result<Self, AnalysisError> {
+        let (yule, butter, filter_rate): (&'static [f64], &'static [f64], u32) =
+            if rate > 0 && rate.is_multiple_of(44100) {
+                (&YULE_44100, &BUTTER_44100, 44100)
+            } else if rate > 0 && rate.is_multiple_of(48000) {
+                (&YULE_48000, &BUTTER_48000, 48000)
+            } else {
+                return Err(AnalysisError::Measure(format!(
+                    "ReplayGain 1 doesn't support {}Hz, only multiples of 44100 and 48000Hz",
+                    rate
+                )));
+            };
+        let decimation = (rate / filter_rate) as usize;
+        // Butterworth sections, of the Q of each pair of poles
+        let low_pass = || {
+            if decimation == 1 {
+                return Vec::new();
+            }
+            (0..DECIMATION_ORDER / 2)
+                .map(|k| {
+                    let angle = (2 * k + 1) as f64 * PI / (2 * DECIMATION_ORDER) as f64;
+                    let q = 1.0 / (2.0 * angle.cos());
+                    weighting::Filter::new(Biquad::low_pass(rate, DECIMATION_CUTOFF, q))
+                })
+                .collect()
+        };
+        Ok(ReplayGain {
+            filters: (0..channels)
+                .map(|_| (Filter::new(yule), Filter::new(butter)))
+                .collect(),
+            decimation,
+            low_pass: (0..channels).map(|_| low_pass()).collect(),
+            skipped: 0,
+            frame: vec![0.0; channels as usize],
+            block_frames: (filter_rate as f64 * BLOCK_TIME).ceil() as usize,
+            block_sum: 0.0,
+            block_count: 0,
+            histogram: vec![0; (STEPS_PER_DB * MAX_DB) as usize],
+        })
+    }
+
+    /// Measures interleaved samples.
+    pub fn add(&mut self, samples: &[f32]) {
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact(channels) {
+            for ((sample, low_pass), decimated) in
+                frame.iter().zip(&mut self.low_pass).zip(&mut self.frame)
+            {
+                *decimated = low_pass
+                    .iter_mut()
+                    .fold(*sample as f64, |sample, filter| filter.process(sample));
+            }
+            if self.skipped > 0 {
+                self.skipped -= 1;
+                continue;
+            }
+            self.skipped = self.decimation - 1;
+            for (sample, (yule, butter)) in self.frame.iter().zip(self.filters.iter_mut()) {
+                // the levels are those of 16 bits samples
+                let filtered = butter.process(yule.process(sample * 32768.0));
+                self.block_sum += filtered * filtered;
+            }
+            self.block_count += 1;
+            if self.block_count == self.block_frames {
+                let mean = self.block_sum / (self.block_frames * channels) as f64;
+                let level = 10.0 * (mean + 1e-37).log10();
+                let last = self.histogram.len() - 1;
+                let step = (level * STEPS_PER_DB).max(0.0) as usize;
+                self.histogram[step.min(last)] += 1;
+                self.block_sum = 0.0;
+                self.block_count = 0;
+            }
+        }
+    }
+
+    /// The gain in dB bringing the audio to the ReplayGain reference level,
+    /// if there was a whole block of it.
+    pub fn gain(&self) -> Option<f64> {
+        let blocks: u64 = self.histogram.iter().sum();
+        if blocks == 0 {
+            return None;
+        }
+        let mut above = (blocks as f64 * (1.0 - PERCENTILE)).ceil() as i64;
+        let mut step = self.histogram.len() - 1;
+        while step > 0 {
+            above -= self.histogram[step] as i64;
+            if above <= 0 {
+                break;
+            }
+            step -= 1;
+        }
+        Some(PINK_REFERENCE - step as f64 / STEPS_PER_DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32, frames: usize) -> Vec<f32> {
+        sine_at(amplitude, frames, 44100)
+    }
+
+    fn sine_at(amplitude: f32, frames: usize, rate: u32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample =
+                    amplitude * (i as f32 * 1000.0 / rate as f32 * std::f32::consts::TAU).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_gain_follows_the_level() {
+        let gain = |amplitude| {
+            let mut replaygain = ReplayGain::new(2, 44100).unwrap();
+            replaygain.add(&sine(amplitude, 44100));
+            replaygain.gain().unwrap()
+        };
+        assert!((gain(0.25) - gain(0.5) - 6.02).abs() < 0.02);
+
+        // not a single block
+        let mut replaygain = ReplayGain::new(2, 44100).unwrap();
+        replaygain.add(&sine(0.5, 2000));
+        assert_eq!(replaygain.gain(), None);
+        assert!(ReplayGain::new(2, 22050).is_err());
+    }
+
+    #[test]
+    fn multiples_of_the_rates_are_taken_down_to_them() {
+        let gain = |rate: u32| {
+            let mut replaygain = ReplayGain::new(2, rate).unwrap();
+            replaygain.add(&sine_at(0.5, rate as usize, rate));
+            replaygain.gain().unwrap()
+        };
+        assert!((gain(88200) - gain(44100)).abs() < 0.05);
+        assert!((gain(96000) - gain(48000)).abs() < 0.05);
+        assert!((gain(192000) - gain(48000)).abs() < 0.05);
+    }
+}
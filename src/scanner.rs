@@ -1,22 +1,165 @@
 use clap::ArgMatches;
 use log::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::analysis::scan_loudness;
+use crate::analysis::{
+    scan_loudness, AnalysisOptions, CommandDecoder, DecoderRegistry, LoudnessAlgorithm, Weighting,
+};
 use crate::error::AppError;
+use crate::timeline::{timeline_path, write_timeline, TimelineFormat};
 use crate::utils::linear_to_db;
 
 pub fn run(matches: &ArgMatches) -> Result<(), AppError> {
-    let paths = matches.get_many::<String>("input").ok_or("no input provided")?;
+    let paths = matches
+        .get_many::<String>("input")
+        .ok_or("no input provided")?;
+    let mut decoders = DecoderRegistry::default();
+    if let Some(template) = matches.get_one::<String>("decoder-command") {
+        decoders.set_fallback(Arc::new(CommandDecoder::new(template)?));
+    }
+    let timeline_format = matches
+        .get_one::<String>("timeline")
+        .map(|format| format.parse::<TimelineFormat>())
+        .transpose()?;
+    let timeline_hop: u64 = matches
+        .get_one::<String>("timeline-hop")
+        .ok_or("no timeline hop provided")?
+        .parse()
+        .map_err(|_| "the timeline hop must be a number of milliseconds")?;
+    if timeline_hop == 0 {
+        return Err("the timeline hop must be at least 1 millisecond".into());
+    }
+    let timeline_dir = Path::new(
+        matches
+            .get_one::<String>("timeline-dir")
+            .ok_or("no timeline directory provided")?,
+    );
+    let options = AnalysisOptions {
+        stems: matches.get_flag("stems"),
+        decoders: Arc::new(decoders),
+        tolerant: matches.get_flag("tolerant"),
+        channel_layout: matches
+            .get_one::<String>("channel-layout")
+            .map(|layout| layout.parse())
+            .transpose()?,
+        timeline_hop: timeline_format.map(|_| Duration::from_millis(timeline_hop)),
+        silence_threshold: matches
+            .get_one::<String>("silence-threshold")
+            .map(|threshold| threshold.parse())
+            .transpose()?,
+        exclude_silence: matches.get_flag("exclude-silence"),
+        algorithm: matches
+            .get_one::<String>("algorithm")
+            .ok_or("no loudness algorithm provided")?
+            .parse()?,
+        weighting: match matches.get_one::<String>("weighting-file") {
+            Some(path) => Weighting::from_file(Path::new(path))?,
+            None => matches
+                .get_one::<String>("weighting")
+                .ok_or("no weighting provided")?
+                .parse()?,
+        },
+        stereo: matches.get_flag("stereo"),
+        max_mono_loss: matches
+            .get_one::<String>("max-mono-loss")
+            .ok_or("no maximum mono loss provided")?
+            .parse()?,
+        band_energy: matches.get_flag("band-energy"),
+        ..Default::default()
+    };
+    if options.weighting != Weighting::K && options.algorithm != LoudnessAlgorithm::Bs1770 {
+        return Err("other weightings than k only apply to the bs1770 algorithm".into());
+    }
     paths.for_each(|path| {
-        match scan_loudness(path) {
+        match scan_loudness(path, &options) {
             Ok(loudness) => {
                 println!(
-                    "{}\n\tIntegrated loudness: {:.2}dB LUFS\n\tTrue peak: {:.2} ({:.2}dB)",
+                    "{}\n\tFormat: {}\n\tIntegrated loudness: {:.2}dB LUFS\n\tTrue peak: {:.2} ({:.2}dB)",
                     path,
+                    loudness.format.as_deref().unwrap_or("unknown"),
                     loudness.integrated_loudness,
                     loudness.true_peak,
                     linear_to_db(loudness.true_peak)
                 );
+                if loudness.algorithm != LoudnessAlgorithm::Bs1770 {
+                    println!(
+                        "\tLoudness ({}): {:.2}dB",
+                        loudness.algorithm.name(),
+                        loudness.loudness
+                    );
+                } else if options.weighting != Weighting::K {
+                    println!(
+                        "\tLoudness ({} weighting): {:.2}dB LUFS",
+                        options.weighting.name(),
+                        loudness.loudness
+                    );
+                }
+                if let Some(loudness_range) = loudness.loudness_range {
+                    println!("\tLoudness range: {:.2} LU", loudness_range);
+                }
+                if let Some(dynamics) = loudness.dynamics {
+                    println!(
+                        "\tPLR: {:.2} LU\n\tPSR: {:.2} LU\n\tCrest factor: {:.2}dB",
+                        dynamics.plr, dynamics.psr, dynamics.crest_factor
+                    );
+                }
+                if let Some(duration) = loudness.duration().filter(|_| options.tolerant) {
+                    println!("\tMeasured: {:.2}s", duration);
+                }
+                if let Some(stereo) = loudness.stereo {
+                    let flag = if stereo.mono_loudness_delta < -options.max_mono_loss {
+                        " (loses level in mono)"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "\tCorrelation: {:.2}, below 150Hz: {:.2}\n\tMono sum: {:.2} LU{}",
+                        stereo.correlation,
+                        stereo.low_end_correlation,
+                        stereo.mono_loudness_delta,
+                        flag
+                    );
+                }
+                if let Some(bands) = loudness.band_energy {
+                    println!(
+                        "\tBand energy: sub {:.2}dB, bass {:.2}dB, low-mid {:.2}dB, mid {:.2}dB, high {:.2}dB",
+                        bands.sub, bands.bass, bands.low_mid, bands.mid, bands.high
+                    );
+                }
+                if let Some(silence) = loudness.silence {
+                    println!(
+                        "\tLeading silence: {:.2}s\n\tTrailing silence: {:.2}s",
+                        silence.leading, silence.trailing
+                    );
+                }
+                for stem in loudness.stems.iter().flatten() {
+                    println!(
+                        "\t{}: {:.2}dB LUFS, true peak {:.2} ({:.2}dB), loudness range {:.2} LU",
+                        stem.name,
+                        stem.integrated_loudness,
+                        stem.true_peak,
+                        linear_to_db(stem.true_peak),
+                        stem.loudness_range
+                    );
+                }
+                if let (Some(format), Some(timeline)) = (timeline_format, &loudness.timeline) {
+                    let timeline_file = timeline_path(timeline_dir, path, format);
+                    let written = File::create(&timeline_file)
+                        .map_err(AppError::from)
+                        .and_then(|file| {
+                            let mut writer = BufWriter::new(file);
+                            write_timeline(&mut writer, path, timeline, format)?;
+                            Ok(writer.flush()?)
+                        });
+                    match written {
+                        Ok(()) => println!("\tTimeline: {}", timeline_file.display()),
+                        Err(e) => error!("{}: {}", timeline_file.display(), e),
+                    }
+                }
             }
             Err(e) => {
                 error!("{}", e);
@@ -0,0 +1,181 @@
+use ebur128::Channel;
+use serde::Serialize;
+
+use crate::error::AnalysisError;
+use crate::weighting::{Biquad, Filter, GatedLoudness, Weighting, ABSOLUTE_GATE};
+
+/// Frequency below which the low end is, in Hz: what club systems often play
+/// in mono.
+const LOW_END: f64 = 150.0;
+
+/// How a stereo track holds up when its channels are summed to mono.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Stereo {
+    /// Correlation of the left and right channels, from -1 when they are out
+    /// of phase to 1 when they are the same.
+    pub correlation: f32,
+    /// Correlation of the channels below 150Hz.
+    pub low_end_correlation: f32,
+    /// Loudness of the mono sum of the channels less that of the track, in
+    /// LU: what's lost on a mono system. Nothing for silence, and a mono sum
+    /// below the absolute gate of BS.1770 is taken at it.
+    pub mono_loudness_delta: f32,
+}
+
+/// Sums of the products of the samples of two channels.
+#[derive(Default)]
+struct Correlation {
+    left: f64,
+    right: f64,
+    product: f64,
+}
+
+impl Correlation {
+    fn add(&mut self, left: f64, right: f64) {
+        self.left += left * left;
+        self.right += right * right;
+        self.product += left * right;
+    }
+
+    /// The correlation, 0 if a channel is silent.
+    fn value(&self) -> f32 {
+        let energy = (self.left * self.right).sqrt();
+        if energy > 0.0 {
+            (self.product / energy) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Measures the correlation of the channels of stereo audio, and the loudness
+/// of their mono sum, both played on the two speakers.
+pub struct StereoAnalysis {
+    correlation: Correlation,
+    low_end_correlation: Correlation,
+    /// Fourth order low-pass filters of each channel.
+    low_pass: [[Filter; 2]; 2],
+    stereo: GatedLoudness,
+    mono: GatedLoudness,
+    /// Mono sum of the samples being measured, kept from one call to the
+    /// next.
+    sum: Vec<f32>,
+}
+
+impl StereoAnalysis {
+    pub fn new(rate: u32) -> Result<Self, AnalysisError> {
+        let k_weighting = Weighting::K.filters(rate)?;
+        let speakers = [Channel::Left, Channel::Right];
+        // a Linkwitz-Riley filter, two Butterworth ones in a row
+        let low_pass = || {
+            let butterworth = Biquad::low_pass(rate, LOW_END, std::f64::consts::FRAC_1_SQRT_2);
+            [Filter::new(butterworth.clone()), Filter::new(butterworth)]
+        };
+        Ok(StereoAnalysis {
+            correlation: Correlation::default(),
+            low_end_correlation: Correlation::default(),
+            low_pass: [low_pass(), low_pass()],
+            stereo: GatedLoudness::new(&k_weighting, &speakers, rate),
+            mono: GatedLoudness::new(&k_weighting, &speakers, rate),
+            sum: Vec::new(),
+        })
+    }
+
+    /// Measures interleaved stereo samples.
+    pub fn add(&mut self, samples: &[f32]) {
+        self.sum.clear();
+        for frame in samples.chunks_exact(2) {
+            let (left, right) = (frame[0] as f64, frame[1] as f64);
+            self.correlation.add(left, right);
+            let [left_low_pass, right_low_pass] = &mut self.low_pass;
+            let low = |filters: &mut [Filter; 2], sample: f64| {
+                filters
+                    .iter_mut()
+                    .fold(sample, |sample, filter| filter.process(sample))
+            };
+            self.low_end_correlation
+                .add(low(left_low_pass, left), low(right_low_pass, right));
+            let sum = (frame[0] + frame[1]) / 2.0;
+            self.sum.extend([sum, sum]);
+        }
+        self.stereo.add(samples);
+        self.mono.add(&self.sum);
+    }
+
+    pub fn finish(&self) -> Stereo {
+        let stereo = self.stereo.loudness();
+        let mono_loudness_delta = if stereo.is_finite() {
+            (self.mono.loudness().max(ABSOLUTE_GATE) - stereo) as f32
+        } else {
+            0.0
+        };
+        Stereo {
+            correlation: self.correlation.value(),
+            low_end_correlation: self.low_end_correlation.value(),
+            mono_loudness_delta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One second of stereo sines at `frequency`, the right one shifted by
+    /// `phase`.
+    fn sines(frequency: f32, phase: f32) -> Vec<f32> {
+        (0..48000)
+            .flat_map(|i| {
+                let t = i as f32 * frequency / 48000.0 * std::f32::consts::TAU;
+                [0.5 * t.sin(), 0.5 * (t + phase).sin()]
+            })
+            .collect()
+    }
+
+    fn analyse(samples: &[f32]) -> Stereo {
+        let mut analysis = StereoAnalysis::new(48000).unwrap();
+        analysis.add(samples);
+        analysis.finish()
+    }
+
+    #[test]
+    fn mono_audio_loses_nothing() {
+        let stereo = analyse(&sines(60.0, 0.0));
+        assert!((stereo.correlation - 1.0).abs() < 0.001);
+        assert!((stereo.low_end_correlation - 1.0).abs() < 0.001);
+        assert!(stereo.mono_loudness_delta.abs() < 0.01);
+    }
+
+    #[test]
+    fn out_of_phase_bass_disappears_in_mono() {
+        let stereo = analyse(&sines(60.0, std::f32::consts::PI));
+        assert!((stereo.correlation + 1.0).abs() < 0.001);
+        assert!((stereo.low_end_correlation + 1.0).abs() < 0.001);
+        assert!(stereo.mono_loudness_delta < -55.0);
+        assert!(stereo.mono_loudness_delta.is_finite());
+
+        // a quarter turn apart, half of it is left
+        let stereo = analyse(&sines(60.0, std::f32::consts::FRAC_PI_2));
+        assert!(stereo.correlation.abs() < 0.01);
+        assert!((stereo.mono_loudness_delta + 3.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn silence_loses_nothing() {
+        let stereo = analyse(&[0.0; 96000]);
+        assert_eq!(stereo.correlation, 0.0);
+        assert_eq!(stereo.low_end_correlation, 0.0);
+        assert_eq!(stereo.mono_loudness_delta, 0.0);
+    }
+
+    #[test]
+    fn the_low_end_is_measured_alone() {
+        // treble out of phase, on top of bass in phase
+        let bass = sines(60.0, 0.0);
+        let treble = sines(5000.0, std::f32::consts::PI);
+        let samples: Vec<f32> = bass.iter().zip(&treble).map(|(b, t)| b + t).collect();
+        let stereo = analyse(&samples);
+        assert!(stereo.correlation.abs() < 0.1);
+        assert!(stereo.low_end_correlation > 0.99);
+    }
+}
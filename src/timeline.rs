@@ -0,0 +1,158 @@
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::analysis::LoudnessPoint;
+use crate::error::AppError;
+
+/// How loudness timelines are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimelineFormat {
+    Csv,
+    Json,
+}
+
+impl TimelineFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            TimelineFormat::Csv => "csv",
+            TimelineFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for TimelineFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(TimelineFormat::Csv),
+            "json" => Ok(TimelineFormat::Json),
+            _ => Err(format!("unknown timeline format: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonTimeline<'a> {
+    path: &'a str,
+    points: &'a [LoudnessPoint],
+}
+
+/// Where the timeline of the file at `path` is written in `directory`: next to
+/// the others, named after the file, extension included so that tracks only
+/// differing by their format don't share one, and a hash of its path so that
+/// tracks of the same name in different folders don't either.
+pub fn timeline_path(directory: &Path, path: &str, format: TimelineFormat) -> PathBuf {
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "timeline".to_string());
+    directory.join(format!(
+        "{}.{:08x}.loudness.{}",
+        name,
+        path_hash(path),
+        format.extension()
+    ))
+}
+
+/// 32 bits FNV-1a hash of `path`, the same from one run to the next.
+fn path_hash(path: &str) -> u32 {
+    path.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Writes the timeline of the file at `path`. Silent points, of minus infinity
+/// loudness, are left empty in CSV and null in JSON.
+pub fn write_timeline(
+    writer: &mut dyn Write,
+    path: &str,
+    points: &[LoudnessPoint],
+    format: TimelineFormat,
+) -> Result<(), AppError> {
+    match format {
+        TimelineFormat::Csv => {
+            let loudness = |lufs: f64| {
+                if lufs.is_finite() {
+                    format!("{:.2}", lufs)
+                } else {
+                    String::new()
+                }
+            };
+            writeln!(writer, "time,momentary,short_term")?;
+            for point in points {
+                writeln!(
+                    writer,
+                    "{:.3},{},{}",
+                    point.time,
+                    loudness(point.momentary),
+                    loudness(point.short_term)
+                )?;
+            }
+        }
+        TimelineFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, &JsonTimeline { path, points })
+                .map_err(|e| AppError::GenericError(e.to_string()))?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<LoudnessPoint> {
+        vec![
+            LoudnessPoint {
+                time: 0.1,
+                momentary: f64::NEG_INFINITY,
+                short_term: f64::NEG_INFINITY,
+            },
+            LoudnessPoint {
+                time: 0.2,
+                momentary: -12.345,
+                short_term: -20.0,
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_timeline() {
+        let mut csv = Vec::new();
+        write_timeline(&mut csv, "track.mp3", &points(), TimelineFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time,momentary,short_term\n0.100,,\n0.200,-12.35,-20.00\n"
+        );
+    }
+
+    #[test]
+    fn json_timeline() {
+        let mut json = Vec::new();
+        write_timeline(&mut json, "track.mp3", &points(), TimelineFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["path"], "track.mp3");
+        assert_eq!(value["points"][0]["momentary"], serde_json::Value::Null);
+        assert_eq!(value["points"][1]["short_term"], -20.0);
+    }
+
+    #[test]
+    fn timelines_are_named_after_the_file() {
+        let timeline = timeline_path(Path::new("out"), "music/track.flac", TimelineFormat::Json);
+        assert_eq!(timeline.parent(), Some(Path::new("out")));
+        let name = timeline.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("track.flac."));
+        assert!(name.ends_with(".loudness.json"));
+    }
+
+    #[test]
+    fn files_of_the_same_name_have_their_own_timeline() {
+        let timeline = |path| timeline_path(Path::new("out"), path, TimelineFormat::Csv);
+        assert_ne!(timeline("a/01 Intro.mp3"), timeline("b/01 Intro.mp3"));
+        assert_eq!(timeline("a/01 Intro.mp3"), timeline("a/01 Intro.mp3"));
+    }
+}
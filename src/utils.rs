@@ -2,6 +2,10 @@ pub fn loudness_to_gain(loudness: f32, target: f32) -> f32 {
     target - loudness
 }
 
+pub fn db_to_linear(db: f32) -> f32 {
+    10_f32.powf(db / 20.)
+}
+
 pub fn linear_to_db(linear: f32) -> f32 {
     if linear == 0. {
         // assume 24bit dynamic range
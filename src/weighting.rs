@@ -0,0 +1,343 @@
+use ebur128::Channel;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::{AnalysisError, AppError};
+
+/// Loudness in LUFS below which blocks are left out of the measurement.
+pub const ABSOLUTE_GATE: f64 = -70.0;
+
+/// How far in LU below the loudness of the blocks above the absolute gate the
+/// blocks left out are.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Number of 100ms steps in a gating block.
+const BLOCK_STEPS: usize = 4;
+
+/// A second order IIR filter, its coefficients normalized so that `a0` is 1.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 2],
+}
+
+impl Biquad {
+    /// High shelf of the K-weighting, modelling the effect of the head: +4dB
+    /// above 1.5kHz or so.
+    fn shelf(rate: u32) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate as f64).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    /// Low-pass filter cutting above `f0`.
+    pub fn low_pass(rate: u32, f0: f64, q: f64) -> Self {
+        let k = (PI * f0 / rate as f64).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let b0 = k * k / a0;
+        Biquad {
+            b: [b0, 2.0 * b0, b0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    /// High-pass filter cutting below `f0`, letting what's above it through
+    /// untouched.
+    pub fn unity_high_pass(rate: u32, f0: f64, q: f64) -> Self {
+        let k = (PI * f0 / rate as f64).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0 / a0, -2.0 / a0, 1.0 / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+
+    /// High-pass filter cutting below `f0`. As in BS.1770, its numerator
+    /// isn't normalized, which is what the -0.691 of the loudness makes up
+    /// for.
+    fn high_pass(rate: u32, f0: f64, q: f64) -> Self {
+        let k = (PI * f0 / rate as f64).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        }
+    }
+}
+
+/// A `Biquad` with its state for a channel, in direct form I.
+pub struct Filter {
+    biquad: Biquad,
+    inputs: [f64; 2],
+    outputs: [f64; 2],
+}
+
+impl Filter {
+    pub fn new(biquad: Biquad) -> Self {
+        Filter {
+            biquad,
+            inputs: [0.0; 2],
+            outputs: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let Biquad { b, a } = &self.biquad;
+        let output = b[0] * input + b[1] * self.inputs[0] + b[2] * self.inputs[1]
+            - a[0] * self.outputs[0]
+            - a[1] * self.outputs[1];
+        self.inputs = [input, self.inputs[0]];
+        self.outputs = [output, self.outputs[0]];
+        output
+    }
+}
+
+/// Filters weighting the frequencies of the audio before its loudness is
+/// measured.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Weighting {
+    /// The K-weighting of BS.1770.
+    #[default]
+    K,
+    /// The K-weighting without its low-frequency roll-off, only cutting below
+    /// 20Hz: sub-bass counts as much as the mids, as on a club system.
+    FlatLowEnd,
+    /// Biquads read from a file, applied in turn, for each sample rate.
+    Custom(Arc<BTreeMap<u32, Vec<Biquad>>>),
+}
+
+impl Weighting {
+    /// Name of the weighting, as given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Weighting::K => "k",
+            Weighting::FlatLowEnd => "flat-low-end",
+            Weighting::Custom(_) => "custom",
+        }
+    }
+
+    /// Reads custom coefficients from a JSON file, an object of the biquads
+    /// for each sample rate: `{"48000": [{"b": [b0, b1, b2], "a": [a1, a2]}]}`.
+    pub fn from_file(path: &Path) -> Result<Self, AppError> {
+        let file = File::open(path)?;
+        let filters: BTreeMap<u32, Vec<Biquad>> = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| AppError::GenericError(format!("{}: {}", path.display(), e)))?;
+        Ok(Weighting::Custom(Arc::new(filters)))
+    }
+
+    /// The biquads weighting audio at `rate`, applied in turn.
+    pub fn filters(&self, rate: u32) -> Result<Vec<Biquad>, AnalysisError> {
+        match self {
+            Weighting::K => Ok(vec![
+                Biquad::shelf(rate),
+                Biquad::high_pass(rate, 38.13547087602444, 0.5003270373238773),
+            ]),
+            Weighting::FlatLowEnd => Ok(vec![
+                Biquad::shelf(rate),
+                Biquad::high_pass(rate, 20.0, std::f64::consts::FRAC_1_SQRT_2),
+            ]),
+            Weighting::Custom(filters) => filters.get(&rate).cloned().ok_or_else(|| {
+                AnalysisError::Measure(format!("the weighting has no filters for {}Hz", rate))
+            }),
+        }
+    }
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "k" => Ok(Weighting::K),
+            "flat-low-end" => Ok(Weighting::FlatLowEnd),
+            _ => Err(format!("unknown weighting: {}", s)),
+        }
+    }
+}
+
+/// Measures the integrated loudness of audio as BS.1770 does, gating 400ms
+/// blocks overlapping by 75%, but through the filters of any weighting.
+pub struct GatedLoudness {
+    /// Filters of each channel, applied in turn.
+    filters: Vec<Vec<Filter>>,
+    /// How much each channel counts, by the speaker it feeds.
+    weights: Vec<f64>,
+    step_frames: usize,
+    /// Weighted sum of the squares of the filtered samples of the current
+    /// step, and how many frames it has so far.
+    step_sum: f64,
+    step_count: usize,
+    /// Mean square of the last steps, enough for a block.
+    steps: Vec<f64>,
+    /// Mean square of each block above the absolute gate.
+    blocks: Vec<f64>,
+}
+
+impl GatedLoudness {
+    pub fn new(biquads: &[Biquad], channel_map: &[Channel], rate: u32) -> Self {
+        GatedLoudness {
+            filters: channel_map
+                .iter()
+                .map(|_| {
+                    biquads
+                        .iter()
+                        .map(|biquad| Filter::new(biquad.clone()))
+                        .collect()
+                })
+                .collect(),
+            weights: channel_map
+                .iter()
+                .map(|channel| match channel {
+                    Channel::Unused => 0.0,
+                    Channel::LeftSurround
+                    | Channel::RightSurround
+                    | Channel::Mp060
+                    | Channel::Mm060
+                    | Channel::Mp090
+                    | Channel::Mm090 => 1.41,
+                    Channel::DualMono => 2.0,
+                    _ => 1.0,
+                })
+                .collect(),
+            step_frames: ((rate as usize + 5) / 10).max(1),
+            step_sum: 0.0,
+            step_count: 0,
+            steps: Vec::with_capacity(BLOCK_STEPS),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn add(&mut self, samples: &[f32]) {
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact(channels) {
+            for ((sample, filters), weight) in
+                frame.iter().zip(self.filters.iter_mut()).zip(&self.weights)
+            {
+                let filtered = filters
+                    .iter_mut()
+                    .fold(*sample as f64, |sample, filter| filter.process(sample));
+                self.step_sum += weight * filtered * filtered;
+            }
+            self.step_count += 1;
+            if self.step_count == self.step_frames {
+                if self.steps.len() == BLOCK_STEPS {
+                    self.steps.remove(0);
+                }
+                self.steps.push(self.step_sum / self.step_frames as f64);
+                if self.steps.len() == BLOCK_STEPS {
+                    let block = self.steps.iter().sum::<f64>() / BLOCK_STEPS as f64;
+                    if loudness(block) >= ABSOLUTE_GATE {
+                        self.blocks.push(block);
+                    }
+                }
+                self.step_sum = 0.0;
+                self.step_count = 0;
+            }
+        }
+    }
+
+    /// The integrated loudness in LUFS, minus infinity when no block is
+    /// above the gates.
+    pub fn loudness(&self) -> f64 {
+        if self.blocks.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+        let threshold = loudness(mean(&self.blocks)) + RELATIVE_GATE;
+        let gated: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|block| loudness(*block) >= threshold)
+            .collect();
+        loudness(mean(&gated))
+    }
+}
+
+/// Loudness in LUFS of a mean square.
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ebur128::{EbuR128, Mode};
+
+    fn sine(frequency: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample =
+                    amplitude * (i as f32 * frequency / 48000.0 * std::f32::consts::TAU).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn gated_loudness(weighting: &Weighting, samples: &[f32]) -> f64 {
+        let filters = weighting.filters(48000).unwrap();
+        let mut meter = GatedLoudness::new(&filters, &[Channel::Left, Channel::Right], 48000);
+        meter.add(samples);
+        meter.loudness()
+    }
+
+    #[test]
+    fn k_weighting_measures_as_ebur128() {
+        // a quiet intro, gated out, then two tones
+        let mut samples = sine(1000.0, 0.01, 48000);
+        samples.extend(sine(60.0, 0.5, 96000));
+        samples.extend(sine(5000.0, 0.25, 96000));
+        let mut ebu = EbuR128::new(2, 48000, Mode::I).unwrap();
+        ebu.add_frames_f32(&samples).unwrap();
+        let expected = ebu.loudness_global().unwrap();
+        assert!((gated_loudness(&Weighting::K, &samples) - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn the_flat_low_end_weighs_sub_bass_more() {
+        let bass = sine(40.0, 0.5, 96000);
+        let mids = sine(1000.0, 0.5, 96000);
+        let difference =
+            |weighting| gated_loudness(weighting, &bass) - gated_loudness(weighting, &mids);
+        assert!(difference(&Weighting::FlatLowEnd) > difference(&Weighting::K) + 1.0);
+        // the mids are measured the same
+        let mids_difference =
+            gated_loudness(&Weighting::FlatLowEnd, &mids) - gated_loudness(&Weighting::K, &mids);
+        assert!(mids_difference.abs() < 0.1);
+    }
+
+    #[test]
+    fn custom_weighting() {
+        let flat = Weighting::Custom(Arc::new(BTreeMap::from([(
+            48000,
+            vec![Biquad {
+                b: [1.0, 0.0, 0.0],
+                a: [0.0, 0.0],
+            }],
+        )])));
+        // unweighted, two full scale sines have a mean square of 0.5 each
+        let loudness = gated_loudness(&flat, &sine(1000.0, 1.0, 48000));
+        assert!((loudness + 0.691).abs() < 0.01);
+        assert!(flat.filters(44100).is_err());
+        assert!(gated_loudness(&flat, &sine(1000.0, 0.0, 48000)).is_infinite());
+    }
+}
@@ -1,5 +1,12 @@
-use dj_library_gain_calculator::analysis::scan_loudness;
+use dj_library_gain_calculator::analysis::{
+    decode, scan_loudness, scan_region, AnalysisError, AnalysisOptions, ChannelLayout,
+    CommandDecoder, ComputedLoudness, Decoder, DecoderRegistry, GainBasis, LoudestSection,
+    LoudnessAlgorithm, LoudnessPoint, Region, SampleSink, Silence, Weighting, CHUNK_FRAMES,
+};
 use std::assert;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use walkdir::WalkDir;
 
 #[test]
@@ -9,7 +16,7 @@ fn loudness_measurement_smoketest() -> Result<(), Box<dyn std::error::Error>> {
         let name = entry.file_name().to_string_lossy();
 
         if name.starts_with("sine-440") {
-            match scan_loudness(&path) {
+            match scan_loudness(&path, &AnalysisOptions::default()) {
                 Ok(computed_loudness) => {
                     let integrated = computed_loudness.integrated_loudness;
                     let peak = computed_loudness.true_peak;
@@ -27,3 +34,950 @@ fn loudness_measurement_smoketest() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[derive(Default)]
+struct ChunkStats {
+    channels: u32,
+    largest_chunk: usize,
+    total_samples: usize,
+}
+
+impl SampleSink for ChunkStats {
+    fn format(&mut self, channels: u32, _rate: u32) -> Result<(), AnalysisError> {
+        self.channels = channels;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), AnalysisError> {
+        self.largest_chunk = self.largest_chunk.max(samples.len());
+        self.total_samples += samples.len();
+        Ok(())
+    }
+}
+
+#[test]
+fn decoders_stream_bounded_chunks() -> Result<(), Box<dyn std::error::Error>> {
+    for entry in WalkDir::new(".").into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path().to_string_lossy();
+        let name = entry.file_name().to_string_lossy();
+
+        if name.starts_with("sine-440") {
+            let mut stats = ChunkStats::default();
+            decode(&path, &mut stats)?;
+            assert!(stats.channels > 0);
+            assert!(stats.largest_chunk <= CHUNK_FRAMES * stats.channels as usize);
+            // The vectors are longer than one chunk.
+            assert!(stats.total_samples > CHUNK_FRAMES * stats.channels as usize);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mp4_priming_and_padding_are_trimmed() -> Result<(), Box<dyn std::error::Error>> {
+    for path in [
+        "tests/vectors/sine-440-aac.m4a",
+        "tests/vectors/sine-440-aac-itunsmpb.m4a",
+        "tests/vectors/sine-440-alac.m4a",
+    ] {
+        let mut stats = ChunkStats::default();
+        decode(path, &mut stats)?;
+        // One second of stereo audio, like the WAV it was encoded from.
+        assert_eq!(stats.total_samples, 44100 * 2);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stem_files_report_each_stem() -> Result<(), Box<dyn std::error::Error>> {
+    let options = AnalysisOptions {
+        stems: true,
+        ..Default::default()
+    };
+    let loudness = scan_loudness("tests/vectors/sine-440.stem.mp4", &options)?;
+    // The master is what the gain is computed from.
+    assert!(loudness.integrated_loudness < -3.5 && loudness.integrated_loudness > -4.2);
+
+    let stems = loudness.stems.expect("stems were measured");
+    let names: Vec<&str> = stems.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["Drums", "Bass", "Synth", "Vox"]);
+    // Each stem of the test vector is half the amplitude of the previous one.
+    for pair in stems.windows(2) {
+        let difference = pair[0].integrated_loudness - pair[1].integrated_loudness;
+        assert!(difference > 5.9 && difference < 6.1);
+    }
+
+    let loudness = scan_loudness(
+        "tests/vectors/sine-440.stem.mp4",
+        &AnalysisOptions::default(),
+    )?;
+    assert!(loudness.stems.is_none());
+
+    // A stem that is all silence doesn't fail the file.
+    let options = AnalysisOptions {
+        silence_threshold: Some(-18.0),
+        exclude_silence: true,
+        ..options
+    };
+    let loudness = scan_loudness("tests/vectors/sine-440.stem.mp4", &options)?;
+    let stems = loudness.stems.unwrap();
+    assert_eq!(stems[3].integrated_loudness, f32::NEG_INFINITY);
+    assert!(stems[2].integrated_loudness.is_finite());
+
+    Ok(())
+}
+
+#[test]
+fn opus_pre_skip_and_end_trimming_are_honoured() -> Result<(), Box<dyn std::error::Error>> {
+    for path in [
+        "tests/vectors/sine-440.opus",
+        "tests/vectors/sine-440-output-gain.opus",
+    ] {
+        let mut stats = ChunkStats::default();
+        decode(path, &mut stats)?;
+        // One second of stereo audio, always decoded at 48kHz.
+        assert_eq!(stats.total_samples, 48000 * 2);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn formats_are_detected_from_content() -> Result<(), Box<dyn std::error::Error>> {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for (vector, misnamed, format) in [
+        ("sine-440-16.wav", "wav-named.mp3", "WAV"),
+        ("sine-440-16.flac", "flac-without-extension", "FLAC"),
+        ("sine-440-16.aiff", "aiff-named.m4a", "AIFF"),
+        ("sine-440.opus", "opus-named.wav", "Ogg"),
+        ("sine-440-aac.m4a", "m4a-named.flac", "MP4"),
+    ] {
+        let path = directory.join(misnamed);
+        std::fs::copy(format!("tests/vectors/{}", vector), &path)?;
+        let loudness = scan_loudness(&path.to_string_lossy(), &AnalysisOptions::default())?;
+        assert_eq!(loudness.format.as_deref(), Some(format));
+        assert!(loudness.integrated_loudness < -3.5 && loudness.integrated_loudness > -4.2);
+    }
+
+    Ok(())
+}
+
+/// Decodes a second of silence from any file with the `.silence` extension.
+struct SilenceDecoder;
+
+impl Decoder for SilenceDecoder {
+    fn name(&self) -> &str {
+        "silence"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["silence", "wav"]
+    }
+
+    fn decode(&self, _path: &str, sink: &mut dyn SampleSink) -> Result<(), AnalysisError> {
+        sink.format(1, 48000)?;
+        sink.write(&[0.0; 48000])
+    }
+}
+
+#[test]
+fn decoders_can_be_registered_and_preferred() -> Result<(), Box<dyn std::error::Error>> {
+    let mut registry = DecoderRegistry::default();
+    assert!(!registry.supported_extensions().contains(&"silence"));
+    registry.register(Arc::new(SilenceDecoder));
+    assert!(registry.supported_extensions().contains(&"silence"));
+    assert!(registry.supported_extensions().contains(&"flac"));
+
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("empty.silence");
+    std::fs::write(&path, b"")?;
+    let mut stats = ChunkStats::default();
+    let name = registry.decode(&path.to_string_lossy(), &mut stats)?;
+    assert_eq!(name, "silence");
+    assert_eq!(stats.total_samples, 48000);
+
+    // Recognising the content comes before the extension...
+    let wav = "tests/vectors/sine-440-16.wav";
+    let names: Vec<&str> = registry.candidates(wav)?.iter().map(|d| d.name()).collect();
+    assert_eq!(names, ["WAV", "silence"]);
+    // ...unless a decoder is preferred.
+    registry.prefer("silence")?;
+    assert_eq!(registry.find(wav)?.name(), "silence");
+    assert!(registry.prefer("nothing").is_err());
+
+    Ok(())
+}
+
+const STUB_DECODER: &str = "sh tests/stub_decoder.sh {input} {channels=2} {rate=44100}";
+
+#[test]
+fn decoder_command_is_the_fallback() -> Result<(), Box<dyn std::error::Error>> {
+    let raw = "tests/vectors/raw-f32-sine-440.raw";
+    assert!(scan_loudness(raw, &AnalysisOptions::default()).is_err());
+
+    let mut decoders = DecoderRegistry::default();
+    decoders.set_fallback(Arc::new(CommandDecoder::new(STUB_DECODER)?));
+    let options = AnalysisOptions {
+        decoders: Arc::new(decoders),
+        ..Default::default()
+    };
+    let loudness = scan_loudness(raw, &options)?;
+    assert_eq!(loudness.format.as_deref(), Some("sh"));
+    assert!(loudness.integrated_loudness < -3.5 && loudness.integrated_loudness > -4.2);
+    assert!(loudness.true_peak > 0.67 && loudness.true_peak < 0.73);
+
+    // Built-in decoders still come first.
+    let loudness = scan_loudness("tests/vectors/sine-440-16.flac", &options)?;
+    assert_eq!(loudness.format.as_deref(), Some("FLAC"));
+
+    let error = scan_loudness("tests/vectors/1_element_collection.nml", &options)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("decoder command failed"));
+    assert!(error.contains("unsupported"));
+
+    Ok(())
+}
+
+#[test]
+fn decoder_command_declares_its_output() {
+    assert!(CommandDecoder::new("ffmpeg -i {input} -f f32le -ac {channels=2} -").is_err());
+    assert!(CommandDecoder::new("ffmpeg -f f32le -ac {channels=2} -ar {rate=48000} -").is_err());
+    assert!(CommandDecoder::new("ffmpeg -i {input} -ac {channels=0} -ar {rate=48000} -").is_err());
+    assert!(CommandDecoder::new("ffmpeg -i {input} -ac {channels=2} -ar {rate=48000} -").is_ok());
+
+    let missing = CommandDecoder::new("no-such-decoder {input} {channels=1} {rate=8000}").unwrap();
+    let error = missing
+        .decode("tests/vectors/sine-440.mp3", &mut ChunkStats::default())
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("not found"));
+}
+
+#[test]
+fn mp3_encoder_delay_and_padding_are_trimmed() -> Result<(), Box<dyn std::error::Error>> {
+    // ID3v2, APEv2 and ID3v1 tags around an Info frame with a LAME tag, then
+    // the frames of sine-440.mp3.
+    let loudness = scan_loudness(
+        "tests/vectors/sine-440-lame.mp3",
+        &AnalysisOptions::default(),
+    )?;
+    assert_eq!(loudness.sample_count, Some(44100));
+
+    // Without a LAME tag, all the decoded samples are measured.
+    let loudness = scan_loudness("tests/vectors/sine-440.mp3", &AnalysisOptions::default())?;
+    assert_eq!(loudness.sample_count, Some(40 * 1152));
+
+    Ok(())
+}
+
+#[test]
+fn broken_files_are_errors() -> Result<(), Box<dyn std::error::Error>> {
+    let options = AnalysisOptions::default();
+    assert!(matches!(
+        scan_loudness("tests/vectors/not_found.wav", &options),
+        Err(AnalysisError::Io(_))
+    ));
+    assert!(matches!(
+        scan_loudness("tests/vectors/1_element_collection.nml", &options),
+        Err(AnalysisError::UnknownFormat(_))
+    ));
+
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for vector in [
+        "sine-440-16.wav",
+        "sine-440-16.flac",
+        "sine-440-16.aiff",
+        "sine-440.ogg",
+        "sine-440.opus",
+        "sine-440-flac.oga",
+        "sine-440-aac.m4a",
+    ] {
+        let data = std::fs::read(format!("tests/vectors/{}", vector))?;
+        // A header without the audio, and a header followed by garbage.
+        let truncated = directory.join(format!("truncated-{}", vector));
+        std::fs::write(&truncated, &data[..64])?;
+        assert!(scan_loudness(&truncated.to_string_lossy(), &options).is_err());
+
+        let mut garbage = data[..64].to_vec();
+        garbage.extend((0..65536_u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8));
+        let garbage_path = directory.join(format!("garbage-{}", vector));
+        std::fs::write(&garbage_path, &garbage)?;
+        let _ = scan_loudness(&garbage_path.to_string_lossy(), &options);
+    }
+
+    // A WAV header with no channels.
+    let mut data = std::fs::read("tests/vectors/sine-440-16.wav")?;
+    data[22] = 0;
+    data[23] = 0;
+    let no_channels = directory.join("no-channels.wav");
+    std::fs::write(&no_channels, &data)?;
+    assert!(scan_loudness(&no_channels.to_string_lossy(), &options).is_err());
+
+    // A WAV fmt chunk claiming to be 4GiB.
+    let mut data = std::fs::read("tests/vectors/sine-440-16.wav")?;
+    data[16..20].copy_from_slice(&0xffff_fff0_u32.to_le_bytes());
+    let huge_fmt = directory.join("huge-fmt.wav");
+    std::fs::write(&huge_fmt, &data)?;
+    assert!(matches!(
+        scan_loudness(&huge_fmt.to_string_lossy(), &options),
+        Err(AnalysisError::Decode(_))
+    ));
+
+    // An MP4 moov box claiming to be 4GiB, and one past the end of the file.
+    let data = std::fs::read("tests/vectors/sine-440-aac.m4a")?;
+    let moov = data.windows(4).position(|t| t == b"moov").unwrap() - 4;
+    for (name, size) in [
+        ("huge-moov.m4a", 0xffff_fff0_u32),
+        ("truncated-moov.m4a", (data.len() - moov + 1) as u32),
+    ] {
+        let mut data = data.clone();
+        data[moov..moov + 4].copy_from_slice(&size.to_be_bytes());
+        let path = directory.join(name);
+        std::fs::write(&path, &data)?;
+        assert!(matches!(
+            scan_loudness(&path.to_string_lossy(), &options),
+            Err(AnalysisError::Decode(_))
+        ));
+    }
+
+    // An AIFF COMM chunk claiming to be 4GiB.
+    let mut data = std::fs::read("tests/vectors/sine-440-16.aiff")?;
+    data[16..20].copy_from_slice(&0xffff_fff0_u32.to_be_bytes());
+    let huge_comm = directory.join("huge-comm.aiff");
+    std::fs::write(&huge_comm, &data)?;
+    assert!(matches!(
+        scan_loudness(&huge_comm.to_string_lossy(), &options),
+        Err(AnalysisError::Decode(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn damaged_flac_frames_are_skipped_when_tolerant() -> Result<(), Box<dyn std::error::Error>> {
+    let mut data = std::fs::read("tests/vectors/sine-440-16.flac")?;
+    // Inside the second frame.
+    data[3000..3100].fill(0x55);
+    let damaged = Path::new(env!("CARGO_TARGET_TMPDIR")).join("damaged-sine-440-16.flac");
+    std::fs::write(&damaged, &data)?;
+    let damaged = damaged.to_string_lossy();
+
+    assert!(scan_loudness(&damaged, &AnalysisOptions::default()).is_err());
+
+    let options = AnalysisOptions {
+        tolerant: true,
+        ..Default::default()
+    };
+    let loudness = scan_loudness(&damaged, &options)?;
+    assert_eq!(loudness.sample_count, Some(44100 - 4096));
+    assert!(loudness.duration().unwrap() < 1.0);
+
+    // Intact files decode the same either way.
+    let loudness = scan_loudness("tests/vectors/sine-440-16.flac", &options)?;
+    assert_eq!(loudness.sample_count, Some(44100));
+
+    // Large metadata blocks, such as pictures, are skipped.
+    let mut padded = data[..4 + 4 + 34].to_vec();
+    let last = padded[4] & 0x80;
+    padded[4] &= 0x7f;
+    let padding = 5 * 1024 * 1024;
+    padded.extend_from_slice(&[0x01 | last, 0x50, 0x00, 0x00]);
+    padded.resize(padded.len() + padding, 0);
+    padded.extend_from_slice(&data[4 + 4 + 34..]);
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("padded-sine-440-16.flac");
+    std::fs::write(&path, &padded)?;
+    let loudness = scan_loudness(&path.to_string_lossy(), &options)?;
+    assert_eq!(loudness.sample_count, Some(44100 - 4096));
+
+    Ok(())
+}
+
+/// A WAV file, with a `WAVE_FORMAT_EXTENSIBLE` header when `channel_mask` is
+/// given.
+fn wav_file(
+    format_tag: u16,
+    channels: u16,
+    container_bits: u16,
+    channel_mask: Option<(u16, u32)>,
+    data: &[u8],
+) -> Vec<u8> {
+    let block_align = channels * container_bits / 8;
+    let mut fmt = Vec::new();
+    let tag = if channel_mask.is_some() {
+        0xfffe
+    } else {
+        format_tag
+    };
+    fmt.extend(tag.to_le_bytes());
+    fmt.extend(channels.to_le_bytes());
+    fmt.extend(44100_u32.to_le_bytes());
+    fmt.extend((44100 * block_align as u32).to_le_bytes());
+    fmt.extend(block_align.to_le_bytes());
+    fmt.extend(container_bits.to_le_bytes());
+    if let Some((valid_bits, mask)) = channel_mask {
+        fmt.extend(22_u16.to_le_bytes());
+        fmt.extend(valid_bits.to_le_bytes());
+        fmt.extend(mask.to_le_bytes());
+        fmt.extend(format_tag.to_le_bytes());
+        fmt.extend(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71");
+    }
+    let mut file = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+    file.extend((fmt.len() as u32).to_le_bytes());
+    file.extend(fmt);
+    file.extend(b"data");
+    file.extend((data.len() as u32).to_le_bytes());
+    file.extend(data);
+    let riff_size = (file.len() - 8) as u32;
+    file[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    file
+}
+
+/// The 16 bits stereo samples of `sine-440-16.wav`.
+fn sine_samples() -> Result<Vec<i16>, Box<dyn std::error::Error>> {
+    let data = std::fs::read("tests/vectors/sine-440-16.wav")?;
+    Ok(data[44..]
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]))
+        .collect())
+}
+
+/// Writes a WAV file to the temporary directory.
+fn write_wav(name: &str, file: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, file)?;
+    Ok(path)
+}
+
+/// Seconds of the sine divided by each divisor, silent for 0, as the data of
+/// a 16 bits stereo WAV file.
+fn sine_sections(sections: &[(i16, usize)]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let samples = sine_samples()?;
+    Ok(sections
+        .iter()
+        .flat_map(|(divisor, seconds)| std::iter::repeat_n(*divisor, *seconds))
+        .flat_map(|divisor| {
+            samples
+                .iter()
+                .map(move |s| s.checked_div(divisor).unwrap_or(0))
+        })
+        .flat_map(|s| s.to_le_bytes())
+        .collect())
+}
+
+/// Ten seconds of the sine, then ten seconds 20dB quieter.
+fn breakdown_samples() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    sine_sections(&[(1, 10), (10, 10)])
+}
+
+fn scan_wav(name: &str, file: &[u8]) -> Result<f32, Box<dyn std::error::Error>> {
+    let path = write_wav(name, file)?;
+    let loudness = scan_loudness(&path.to_string_lossy(), &AnalysisOptions::default())?;
+    Ok(loudness.integrated_loudness)
+}
+
+#[test]
+fn wav_sample_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let samples = sine_samples()?;
+    let pcm_16: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let reference = scan_wav("pcm-16.wav", &wav_file(1, 2, 16, None, &pcm_16))?;
+
+    let unsigned_8: Vec<u8> = samples.iter().map(|s| ((s >> 8) + 128) as u8).collect();
+    let twenty_in_24: Vec<u8> = samples
+        .iter()
+        .flat_map(|s| ((*s as i32) << 8).to_le_bytes()[..3].to_vec())
+        .collect();
+    let float_64: Vec<u8> = samples
+        .iter()
+        .flat_map(|s| (*s as f64 / 32768.0).to_le_bytes())
+        .collect();
+    for (name, file) in [
+        ("pcm-8.wav", wav_file(1, 2, 8, None, &unsigned_8)),
+        (
+            "pcm-20.wav",
+            wav_file(1, 2, 24, Some((20, 0x3)), &twenty_in_24),
+        ),
+        ("float-64.wav", wav_file(3, 2, 64, None, &float_64)),
+    ] {
+        let loudness = scan_wav(name, &file)?;
+        assert!((loudness - reference).abs() < 0.1, "{}: {}", name, loudness);
+    }
+
+    // WAVE_FORMAT_EXTENSIBLE files written by an encoder, of 24 bits
+    // integers and 32 bits floats.
+    for name in ["sine-440-24.wav", "sine-440-32.wav"] {
+        let path = format!("tests/vectors/{}", name);
+        let data = std::fs::read(&path)?;
+        assert_eq!(&data[20..22], &0xfffe_u16.to_le_bytes());
+        let loudness = scan_loudness(&path, &AnalysisOptions::default())?;
+        assert_eq!(loudness.format.as_deref(), Some("WAV"));
+        assert_eq!(loudness.sample_count, Some(44100));
+        let loudness = loudness.integrated_loudness;
+        assert!((loudness - reference).abs() < 0.1, "{}: {}", name, loudness);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn wav_channel_mask_sets_the_weighting() -> Result<(), Box<dyn std::error::Error>> {
+    // The left channel of the sine as the third of four channels.
+    let data: Vec<u8> = sine_samples()?
+        .chunks_exact(2)
+        .flat_map(|frame| [0, 0, frame[0], 0])
+        .flat_map(|s: i16| s.to_le_bytes())
+        .collect();
+
+    // Without a mask, that is a left surround channel, weighted +1.5dB.
+    let quad = scan_wav("quad.wav", &wav_file(1, 4, 16, None, &data))?;
+    // FL FR FC LFE: it is the center channel.
+    let center = scan_wav("3.1.wav", &wav_file(1, 4, 16, Some((16, 0xf)), &data))?;
+    assert!((quad - center - 1.5).abs() < 0.1, "{} {}", quad, center);
+
+    Ok(())
+}
+
+#[test]
+fn channel_layout_override_applies_to_files_without_one() -> Result<(), Box<dyn std::error::Error>>
+{
+    let data: Vec<u8> = sine_samples()?
+        .chunks_exact(2)
+        .flat_map(|frame| [0, 0, frame[0], 0])
+        .flat_map(|s: i16| s.to_le_bytes())
+        .collect();
+    let quad = write_wav("override-quad.wav", &wav_file(1, 4, 16, None, &data))?;
+    let quad = quad.to_string_lossy();
+    let masked = write_wav(
+        "override-masked.wav",
+        &wav_file(1, 4, 16, Some((16, 0x33)), &data),
+    )?;
+    let masked = masked.to_string_lossy();
+    let scan = |path: &str, layout: &str| -> Result<f32, Box<dyn std::error::Error>> {
+        let options = AnalysisOptions {
+            channel_layout: Some(layout.parse()?),
+            ..Default::default()
+        };
+        Ok(scan_loudness(path, &options)?.integrated_loudness)
+    };
+
+    let surround = scan_loudness(&quad, &AnalysisOptions::default())?.integrated_loudness;
+    // The third channel is the center one.
+    let center = scan(&quad, "FL,FR,FC,LFE")?;
+    assert!(
+        (surround - center - 1.5).abs() < 0.1,
+        "{} {}",
+        surround,
+        center
+    );
+    // Only files with as many channels are concerned.
+    assert_eq!(scan(&quad, "FL,FR,FC,LFE,BL,BR")?, surround);
+    // Files telling their layout keep it.
+    assert_eq!(scan(&masked, "FL,FR,FC,LFE")?, surround);
+
+    assert!("FL,FR,XX".parse::<ChannelLayout>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn loudness_range_of_a_breakdown() -> Result<(), Box<dyn std::error::Error>> {
+    let data = breakdown_samples()?;
+    let path = write_wav("breakdown.wav", &wav_file(1, 2, 16, None, &data))?;
+    let loudness = scan_loudness(&path.to_string_lossy(), &AnalysisOptions::default())?;
+
+    let range = loudness.loudness_range.unwrap();
+    assert!(range > 15.0 && range < 20.5, "{}", range);
+
+    // A steady sine has none.
+    let loudness = scan_loudness("tests/vectors/sine-440-16.wav", &AnalysisOptions::default())?;
+    assert!(loudness.loudness_range.unwrap() < 0.1);
+
+    Ok(())
+}
+
+#[test]
+fn timeline_follows_the_loudness() -> Result<(), Box<dyn std::error::Error>> {
+    let data = breakdown_samples()?;
+    let path = write_wav("timeline.wav", &wav_file(1, 2, 16, None, &data))?;
+    let path = path.to_string_lossy();
+
+    let options = AnalysisOptions {
+        timeline_hop: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let timeline = scan_loudness(&path, &options)?.timeline.unwrap();
+    assert_eq!(timeline.len(), 200);
+    assert!((timeline[0].time - 0.1).abs() < 1e-9);
+    assert!((timeline[199].time - 20.0).abs() < 1e-9);
+    // The momentary loudness drops right away, the short-term one over 3s.
+    let (loud, quiet) = (&timeline[98], &timeline[198]);
+    assert!((loud.momentary - quiet.momentary - 20.0).abs() < 0.5);
+    assert!((loud.short_term - quiet.short_term - 20.0).abs() < 0.5);
+    let after_the_drop = &timeline[108];
+    assert!((after_the_drop.momentary - quiet.momentary).abs() < 0.5);
+    assert!(after_the_drop.short_term > quiet.short_term + 5.0);
+
+    // It is only recorded when asked for.
+    let loudness = scan_loudness(&path, &AnalysisOptions::default())?;
+    assert!(loudness.timeline.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn loudest_section_leaves_out_the_intro() -> Result<(), Box<dyn std::error::Error>> {
+    // An intro three times as long as the drop, 6dB below it: not enough for
+    // the gating of the integrated loudness to leave it out.
+    let data = sine_sections(&[(2, 15), (1, 5)])?;
+    let path = write_wav("intro.wav", &wav_file(1, 2, 16, None, &data))?;
+    let path = path.to_string_lossy();
+    let drop = scan_loudness("tests/vectors/sine-440-16.wav", &AnalysisOptions::default())?;
+
+    let section = LoudestSection {
+        length: Duration::from_secs(3),
+        percentile: 100.0,
+    };
+    let basis = GainBasis::LoudestSection(section);
+    let options = AnalysisOptions {
+        gain_basis: basis,
+        ..Default::default()
+    };
+    let loudness = scan_loudness(&path, &options)?;
+    let section_loudness = loudness.gain_loudness(&basis).unwrap();
+    assert!((section_loudness - drop.integrated_loudness).abs() < 0.1);
+    assert!(loudness.integrated_loudness < section_loudness - 1.0);
+    // The loudness of other sections wasn't measured.
+    let other = GainBasis::LoudestSection(LoudestSection {
+        percentile: 50.0,
+        ..section
+    });
+    assert_eq!(loudness.gain_loudness(&other), None);
+    assert_eq!(
+        loudness.gain_loudness(&GainBasis::Integrated),
+        Some(loudness.integrated_loudness)
+    );
+
+    // Tracks shorter than a section are taken whole.
+    let loudness = scan_loudness("tests/vectors/sine-440-16.wav", &options)?;
+    assert_eq!(
+        loudness.gain_loudness(&basis),
+        Some(loudness.integrated_loudness)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn only_the_region_is_measured() -> Result<(), Box<dyn std::error::Error>> {
+    let data = breakdown_samples()?;
+    let path = write_wav("region.wav", &wav_file(1, 2, 16, None, &data))?;
+    let path = path.to_string_lossy();
+    let options = AnalysisOptions {
+        count_overs: true,
+        ..Default::default()
+    };
+    let loud = scan_loudness("tests/vectors/sine-440-16.wav", &options)?.integrated_loudness;
+
+    let region = |start: u64, end: Option<u64>| {
+        Some(Region {
+            start: Duration::from_millis(start),
+            end: end.map(Duration::from_millis),
+        })
+    };
+    let intro = scan_region(&path, &options, region(2_500, Some(7_500)))?;
+    assert!((intro.integrated_loudness - loud).abs() < 0.1);
+    assert_eq!(intro.sample_count, Some(5 * 44100));
+    let outro = scan_region(&path, &options, region(12_000, None))?;
+    assert!((outro.integrated_loudness - (loud - 20.0)).abs() < 0.1);
+    assert_eq!(outro.sample_count, Some(8 * 44100));
+
+    // Only the peaks of the region are counted.
+    let threshold = 20.0 * intro.true_peak.log10() - 10.0;
+    let overs = |loudness: &ComputedLoudness| {
+        let histogram = loudness.peak_histogram.as_ref().unwrap();
+        histogram.overs(0.0, threshold).samples
+    };
+    assert!(overs(&intro) > 4 * 44100);
+    assert_eq!(overs(&outro), 0);
+
+    // Regions past the end have nothing to measure.
+    let error = scan_region(&path, &options, region(30_000, None)).unwrap_err();
+    assert!(matches!(error, AnalysisError::Measure(_)));
+
+    Ok(())
+}
+
+#[test]
+fn silence_at_the_edges() -> Result<(), Box<dyn std::error::Error>> {
+    // Two seconds of digital silence, three of the sine, then one of noise
+    // about 40dB below it.
+    let data = sine_sections(&[(0, 2), (1, 3), (100, 1)])?;
+    let path = write_wav("silence.wav", &wav_file(1, 2, 16, None, &data))?;
+    let path = path.to_string_lossy();
+
+    let options = AnalysisOptions {
+        silence_threshold: Some(-30.0),
+        timeline_hop: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let loudness = scan_loudness(&path, &options)?;
+    let silence = loudness.silence.unwrap();
+    assert!((silence.leading - 2.0).abs() < 0.001, "{:?}", silence);
+    assert!((silence.trailing - 1.0).abs() < 0.001, "{:?}", silence);
+    assert_eq!(loudness.timeline.unwrap().len(), 12);
+
+    // Left out, only the sine is measured, at the time it plays.
+    let options = AnalysisOptions {
+        exclude_silence: true,
+        ..options
+    };
+    let loudness = scan_loudness(&path, &options)?;
+    assert_eq!(loudness.silence, Some(silence));
+    assert_eq!(loudness.duration(), Some(6.0));
+    let timeline = loudness.timeline.unwrap();
+    let times: Vec<f64> = timeline.iter().map(|point| point.time).collect();
+    assert_eq!(times, [2.5, 3.0, 3.5, 4.0, 4.5, 5.0]);
+
+    // Files that are all silence have nothing left to measure.
+    let silent = write_wav("silent.wav", &wav_file(1, 2, 16, None, &[0; 44100 * 4]))?;
+    let error = scan_loudness(&silent.to_string_lossy(), &options).unwrap_err();
+    assert!(matches!(error, AnalysisError::Measure(_)));
+    let options = AnalysisOptions {
+        exclude_silence: false,
+        ..options
+    };
+    let loudness = scan_loudness(&silent.to_string_lossy(), &options)?;
+    assert_eq!(
+        loudness.silence,
+        Some(Silence {
+            leading: 1.0,
+            trailing: 0.0
+        })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn long_silences_in_the_audio_are_measured() -> Result<(), Box<dyn std::error::Error>> {
+    // The sine, three seconds of digital silence, then the sine again: more
+    // silence than is held as it is.
+    let data = sine_sections(&[(1, 1), (0, 3), (1, 1)])?;
+    let path = write_wav("gap.wav", &wav_file(1, 2, 16, None, &data))?;
+    let path = path.to_string_lossy();
+
+    let options = AnalysisOptions {
+        silence_threshold: Some(-30.0),
+        timeline_hop: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let kept = scan_loudness(&path, &options)?;
+    let options = AnalysisOptions {
+        exclude_silence: true,
+        ..options
+    };
+    let excluded = scan_loudness(&path, &options)?;
+    assert_eq!(excluded.silence.unwrap().trailing, 0.0);
+    let times = |timeline: Option<Vec<LoudnessPoint>>| -> Vec<f64> {
+        timeline.unwrap().iter().map(|point| point.time).collect()
+    };
+    // the gap is measured whole, at the time it plays
+    let times = (times(excluded.timeline), times(kept.timeline));
+    assert_eq!(times.0, times.1);
+    assert_eq!(times.0.len(), 10);
+
+    Ok(())
+}
+
+#[test]
+fn loudness_algorithms() -> Result<(), Box<dyn std::error::Error>> {
+    let scan = |path: &str, algorithm: LoudnessAlgorithm| {
+        let options = AnalysisOptions {
+            algorithm,
+            ..Default::default()
+        };
+        scan_loudness(path, &options)
+    };
+    let path = "tests/vectors/sine-440-16.wav";
+    let bs1770 = scan(path, LoudnessAlgorithm::Bs1770)?;
+    assert_eq!(bs1770.algorithm, LoudnessAlgorithm::Bs1770);
+    assert_eq!(bs1770.loudness, bs1770.integrated_loudness);
+
+    // K-weighting does little at 440Hz
+    let rms = scan(path, LoudnessAlgorithm::Rms)?;
+    assert!((rms.loudness - bs1770.integrated_loudness).abs() < 0.1);
+    assert_eq!(rms.integrated_loudness, bs1770.integrated_loudness);
+
+    let peak = scan(path, LoudnessAlgorithm::Peak)?;
+    assert!((peak.loudness - 20.0 * peak.true_peak.log10()).abs() < 0.001);
+    assert_eq!(
+        peak.gain_loudness(&GainBasis::Integrated),
+        Some(peak.loudness)
+    );
+
+    // Halving the level of the sine takes 6dB off ReplayGain's loudness.
+    let replaygain = scan(path, LoudnessAlgorithm::ReplayGain1)?;
+    let data: Vec<u8> = sine_samples()?
+        .iter()
+        .flat_map(|s| (s / 2).to_le_bytes())
+        .collect();
+    let quieter = write_wav("quieter.wav", &wav_file(1, 2, 16, None, &data))?;
+    let quieter = scan(&quieter.to_string_lossy(), LoudnessAlgorithm::ReplayGain1)?;
+    assert!((replaygain.loudness - quieter.loudness - 6.02).abs() < 0.05);
+
+    // Multiples of 44100 and 48000Hz are measured once taken down to them,
+    // other rates with BS.1770.
+    for (rate, algorithm) in [
+        (88200_u32, LoudnessAlgorithm::ReplayGain1),
+        (32000, LoudnessAlgorithm::Bs1770),
+    ] {
+        let mut file = std::fs::read(path)?;
+        file[24..28].copy_from_slice(&rate.to_le_bytes());
+        file[28..32].copy_from_slice(&(rate * 4).to_le_bytes());
+        let resampled = write_wav(&format!("sine-at-{}.wav", rate), &file)?;
+        let loudness = scan(&resampled.to_string_lossy(), LoudnessAlgorithm::ReplayGain1)?;
+        assert_eq!(loudness.algorithm, algorithm);
+    }
+
+    assert_eq!("ReplayGain1".parse(), Ok(LoudnessAlgorithm::ReplayGain1));
+    assert!("replaygain2".parse::<LoudnessAlgorithm>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn weighting_profiles() -> Result<(), Box<dyn std::error::Error>> {
+    let scan = |path: &str, weighting: &Weighting| {
+        let options = AnalysisOptions {
+            weighting: weighting.clone(),
+            ..Default::default()
+        };
+        scan_loudness(path, &options)
+    };
+    let sine = "tests/vectors/sine-440-16.wav";
+    let k = scan(sine, &Weighting::K)?;
+    let flat = scan(sine, &Weighting::FlatLowEnd)?;
+    assert!((flat.loudness - k.loudness).abs() < 0.1);
+    assert_eq!(flat.integrated_loudness, k.integrated_loudness);
+
+    // sub-bass, which K-weighting rolls off
+    let data: Vec<u8> = (0..44100)
+        .flat_map(|i| {
+            let sample = (0.5
+                * (i as f32 * 40.0 / 44100.0 * std::f32::consts::TAU).sin()
+                * i16::MAX as f32) as i16;
+            [sample, sample]
+        })
+        .flat_map(i16::to_le_bytes)
+        .collect();
+    let bass = write_wav("bass.wav", &wav_file(1, 2, 16, None, &data))?;
+    let bass = bass.to_string_lossy();
+    let k = scan(&bass, &Weighting::K)?;
+    let flat = scan(&bass, &Weighting::FlatLowEnd)?;
+    assert!(flat.loudness > k.loudness + 1.0);
+
+    // a filter letting everything through measures the audio unweighted
+    let coefficients = Path::new(env!("CARGO_TARGET_TMPDIR")).join("weighting.json");
+    std::fs::write(
+        &coefficients,
+        r#"{"44100": [{"b": [1.0, 0.0, 0.0], "a": [0.0, 0.0]}]}"#,
+    )?;
+    let unweighted = Weighting::from_file(&coefficients)?;
+    let custom = scan(&bass, &unweighted)?;
+    let rms = scan_loudness(
+        &bass,
+        &AnalysisOptions {
+            algorithm: LoudnessAlgorithm::Rms,
+            ..Default::default()
+        },
+    )?;
+    assert!((custom.loudness - rms.loudness).abs() < 0.05);
+
+    // no filters for 48000Hz
+    std::fs::write(&coefficients, r#"{"48000": []}"#)?;
+    let other_rate = Weighting::from_file(&coefficients)?;
+    assert!(matches!(
+        scan(&bass, &other_rate),
+        Err(AnalysisError::Measure(_))
+    ));
+
+    assert_eq!("flat-low-end".parse(), Ok(Weighting::FlatLowEnd));
+    assert!("a".parse::<Weighting>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn dynamics_of_a_sine() -> Result<(), Box<dyn std::error::Error>> {
+    // long enough for a whole short-term window
+    let data: Vec<u8> = sine_samples()?
+        .repeat(4)
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect();
+    let path = write_wav("long-sine.wav", &wav_file(1, 2, 16, None, &data))?;
+    let loudness = scan_loudness(&path.to_string_lossy(), &AnalysisOptions::default())?;
+
+    let dynamics = loudness.dynamics.unwrap();
+    let true_peak = 20.0 * loudness.true_peak.log10();
+    assert!((dynamics.plr - (true_peak - loudness.integrated_loudness)).abs() < 0.001);
+    // the loudness doesn't change
+    assert!((dynamics.psr - dynamics.plr).abs() < 0.1);
+    // that of a sine
+    assert!((dynamics.crest_factor - 3.01).abs() < 0.02);
+
+    Ok(())
+}
+
+#[test]
+fn stereo_image() -> Result<(), Box<dyn std::error::Error>> {
+    let options = AnalysisOptions {
+        stereo: true,
+        ..Default::default()
+    };
+    let path = "tests/vectors/sine-440-16.wav";
+    let stereo = scan_loudness(path, &options)?.stereo.unwrap();
+    assert!((stereo.correlation - 1.0).abs() < 0.001);
+    assert!(stereo.mono_loudness_delta.abs() < 0.01);
+    assert_eq!(
+        scan_loudness(path, &AnalysisOptions::default())?.stereo,
+        None
+    );
+
+    // the right channel out of phase
+    let data: Vec<u8> = sine_samples()?
+        .chunks_exact(2)
+        .flat_map(|frame| [frame[0], frame[1].saturating_neg()])
+        .flat_map(i16::to_le_bytes)
+        .collect();
+    let out_of_phase = write_wav("out-of-phase.wav", &wav_file(1, 2, 16, None, &data))?;
+    let stereo = scan_loudness(&out_of_phase.to_string_lossy(), &options)?
+        .stereo
+        .unwrap();
+    assert!((stereo.correlation + 1.0).abs() < 0.001);
+    assert!(stereo.mono_loudness_delta < -options.max_mono_loss);
+
+    Ok(())
+}
+
+#[test]
+fn band_energy_of_a_sine() -> Result<(), Box<dyn std::error::Error>> {
+    let options = AnalysisOptions {
+        band_energy: true,
+        ..Default::default()
+    };
+    let path = "tests/vectors/sine-440-16.wav";
+    let bands = scan_loudness(path, &options)?.band_energy.unwrap();
+    // 440Hz is in the low-mid band, near its edge with the mid one
+    assert!(bands.low_mid > bands.mid);
+    assert!(bands.low_mid > -3.0);
+    assert!(bands.sub < -40.0);
+    assert!(bands.high < -40.0);
+    assert_eq!(
+        scan_loudness(path, &AnalysisOptions::default())?.band_energy,
+        None
+    );
+
+    Ok(())
+}
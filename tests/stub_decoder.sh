@@ -0,0 +1,8 @@
+#!/bin/sh
+# Stands for a decoder command in tests: "decodes" raw files, which already
+# are 32 bits float samples, by writing them out. Fails like a decoder would
+# for anything else.
+case "$1" in
+    *.raw) cat "$1" ;;
+    *) echo "unsupported: $1" >&2; exit 1 ;;
+esac